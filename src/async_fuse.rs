@@ -0,0 +1,434 @@
+use fuse::{Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request};
+use libc::{EBADF, EIO, c_int};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use time::Timespec;
+use async_nfs::block_on;
+use super::{request_caller, AsyncAdapter, AsyncNetworkFilesystem, CacheKey, Caller, LibcError, MountOptions, NetFuse, NetworkFilesystem, DEFAULT_TTL};
+
+// Number of worker threads, and so of backend reads and lookups that can be waited on at once
+const WORKERS: usize = 16;
+
+type Served<A> = NetFuse<AsyncAdapter<Arc<A>>>;
+
+// Work for the thread serving requests, run with its `Server`
+pub type Job<A> = Box<dyn FnOnce(&mut Server<A>) + Send>;
+
+// Request waiting for a file to be read into the cache, called with the outcome of the read
+type Waiter<A> = Box<dyn FnOnce(&mut Served<A>, Result<(), LibcError>) + Send>;
+
+// FUSE side of `mount_async`, which hands each request along with its reply to the serving thread,
+// so that the FUSE loop can read the next request while the backend is slow to answer
+pub struct AsyncNetFuse<A: AsyncNetworkFilesystem + Send + Sync + 'static> {
+    jobs: Sender<Job<A>>,
+    // true if the command line of callers is read for each request
+    caller_cmdline: bool,
+}
+
+// State of the serving thread, which owns the `NetFuse` and runs jobs one at a time
+pub struct Server<A: AsyncNetworkFilesystem + Send + Sync + 'static> {
+    netfuse: Served<A>,
+    fs: Arc<A>,
+    // sends the results of workers back to this thread
+    jobs: Sender<Job<A>>,
+    workers: Workers,
+    // files being read into the cache by a worker, with the requests waiting for them
+    reading: HashMap<CacheKey, Vec<Waiter<A>>>,
+    // true once unmounted
+    stopped: bool,
+}
+
+// Threads that each run one closure at a time, in the order they were queued
+struct Workers {
+    queue: Sender<Box<dyn FnOnce() + Send>>,
+}
+
+impl Workers {
+    fn start(count: usize) -> Workers {
+        let (queue, work) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+        let work = Arc::new(Mutex::new(work));
+        for _ in 0..count {
+            let work = work.clone();
+            thread::spawn(move || loop {
+                let next = work.lock().unwrap().recv();
+                match next {
+                    Ok(run) => run(),
+                    Err(_) => return,
+                }
+            });
+        }
+        Workers { queue: queue }
+    }
+}
+
+// Serves the jobs from `queue` with a `NetFuse` for `fs` until it is unmounted. `jobs` sends to `queue`.
+pub fn serve<A>(fs: A, options: &MountOptions, jobs: Sender<Job<A>>, queue: Receiver<Job<A>>)
+    where A: AsyncNetworkFilesystem + Send + Sync + 'static
+{
+    let fs = Arc::new(fs);
+    let mut server = Server {
+        netfuse: NetFuse::new(AsyncAdapter::new(fs.clone()), options),
+        fs: fs,
+        jobs: jobs,
+        workers: Workers::start(WORKERS),
+        reading: HashMap::new(),
+        stopped: false,
+    };
+    while !server.stopped {
+        match queue.recv() {
+            Ok(job) => job(&mut server),
+            Err(_) => break,
+        }
+    }
+}
+
+impl<A: AsyncNetworkFilesystem + Send + Sync + 'static> Server<A> {
+    // Runs `work` on a worker, then `finish` with its result on the serving thread
+    fn run_on_worker<T, W, F>(&self, work: W, finish: F)
+        where T: Send + 'static, W: FnOnce() -> T + Send + 'static, F: FnOnce(&mut Server<A>, T) + Send + 'static
+    {
+        let jobs = self.jobs.clone();
+        let job = Box::new(move || {
+            let result = work();
+            // the result is dropped if the filesystem was unmounted meanwhile
+            let _ = jobs.send(Box::new(move |server: &mut Server<A>| finish(server, result)));
+        });
+        if self.workers.queue.send(job).is_err() {
+            error!("async workers are gone");
+        }
+    }
+
+    // Reads through the handle `fh` like `NetFuse::read_file`, passing the data to `done`. Data that
+    // isn't cached is read by a worker, with one backend read for every request waiting for the same file.
+    fn read_file<F>(&mut self, caller: Caller, fh: u64, offset: u64, size: u32, done: F)
+        where F: FnOnce(Result<&[u8], LibcError>) + Send + 'static
+    {
+        let key = match self.netfuse.file_handles.get(&fh) {
+            Some(handle) if handle.is_readable() => handle.key,
+            _ => return done(Err(EBADF)),
+        };
+        let path = match self.netfuse.fill_cache_locally(key) {
+            Ok(Some(path)) => path,
+            Ok(None) => return done(self.netfuse.read_file(&caller, fh, offset, size)),
+            Err(err) => return done(Err(err)),
+        };
+
+        let reader = caller.clone();
+        let waiter: Waiter<A> = Box::new(move |netfuse: &mut Served<A>, result: Result<(), LibcError>| {
+            done(result.and_then(|_| netfuse.read_file(&caller, fh, offset, size)))
+        });
+        match self.reading.entry(key) {
+            Entry::Occupied(mut waiting) => return waiting.get_mut().push(waiter),
+            Entry::Vacant(waiting) => waiting.insert(Vec::new()).push(waiter),
+        }
+        let fs = self.fs.clone();
+        self.run_on_worker(move || block_on(fs.read(&reader, &path)), move |server, result| {
+            let result = server.netfuse.cache_read(key, result);
+            for waiter in server.reading.remove(&key).unwrap_or_default() {
+                waiter(&mut server.netfuse, result);
+            }
+        });
+    }
+
+    // Looks up `name` in the directory `parent` like `NetFuse::lookup_child`, passing the inode to `done`.
+    // Names that only the backend can resolve are looked up by a worker.
+    fn lookup_child<F>(&mut self, caller: Caller, parent: u64, name: &Path, done: F)
+        where F: FnOnce(&mut Served<A>, Result<u64, LibcError>) + Send + 'static
+    {
+        let path = match self.netfuse.resolve_child(&caller, parent, name) {
+            Ok(Some(ino)) => return done(&mut self.netfuse, Ok(ino)),
+            Ok(None) => self.netfuse.inodes[parent].path.join(name),
+            Err(err) => return done(&mut self.netfuse, Err(err)),
+        };
+
+        let (fs, looker, looked_up) = (self.fs.clone(), caller.clone(), path.clone());
+        self.run_on_worker(move || block_on(fs.lookup(&looker, &looked_up)), move |server, result| {
+            let result = server.netfuse.looked_up(&caller, &path, result);
+            done(&mut server.netfuse, result)
+        });
+    }
+}
+
+impl<A: AsyncNetworkFilesystem + Send + Sync + 'static> AsyncNetFuse<A> {
+    pub fn new(jobs: Sender<Job<A>>, caller_cmdline: bool) -> AsyncNetFuse<A> {
+        AsyncNetFuse { jobs: jobs, caller_cmdline: caller_cmdline }
+    }
+
+    // Queues `job` for the serving thread
+    fn send<F: FnOnce(&mut Server<A>) + Send + 'static>(&self, job: F) {
+        // the reply of a job that can't be run is dropped, which answers the request with EIO
+        if self.jobs.send(Box::new(job)).is_err() {
+            error!("the serving thread is gone");
+        }
+    }
+
+    // Queues `job` for the serving thread along with the caller of `req`
+    fn serve<F: FnOnce(&mut Server<A>, Caller) + Send + 'static>(&self, req: &Request, job: F) {
+        let caller = request_caller(req, self.caller_cmdline);
+        self.send(move |server| job(server, caller));
+    }
+
+    // Runs `job` on the serving thread and waits for its result
+    fn call<T: Send + 'static, F: FnOnce(&mut Server<A>) -> T + Send + 'static>(&self, job: F) -> Option<T> {
+        let (sender, result) = mpsc::channel();
+        self.send(move |server| {
+            let _ = sender.send(job(server));
+        });
+        result.recv().ok()
+    }
+}
+
+// Unmounting writes back dirty data on the serving thread, which then stops
+impl<A: AsyncNetworkFilesystem + Send + Sync + 'static> Drop for AsyncNetFuse<A> {
+    fn drop(&mut self) {
+        self.send(|server| {
+            server.netfuse.unmount();
+            server.stopped = true;
+        });
+    }
+}
+
+impl<A: AsyncNetworkFilesystem + Send + Sync + 'static> Filesystem for AsyncNetFuse<A> {
+
+    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+        self.call(|server| server.netfuse.nfs.init()).unwrap_or(Err(EIO))
+    }
+
+    fn destroy(&mut self, _req: &Request) {
+        self.send(|server| server.netfuse.unmount());
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
+        debug!("lookup(parent={}, name=\"{}\")", parent, name.display());
+        let name = name.to_owned();
+        self.serve(req, move |server, caller| {
+            server.lookup_child(caller, parent, &name, move |netfuse, result| match result {
+                Ok(ino) => {
+                    let inode = &netfuse.inodes[ino];
+                    reply.entry(&DEFAULT_TTL, &inode.attr, inode.generation);
+                }
+                Err(err) => reply.error(err),
+            })
+        });
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        debug!("forget(ino={}, nlookup={})", ino, nlookup);
+        self.send(move |server| server.netfuse.serve_forget(ino, nlookup));
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        self.serve(req, move |server, caller| server.netfuse.serve_getattr(&caller, ino, reply));
+    }
+
+    fn read(&mut self, req: &Request, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+        debug!("read(ino={}, fh={}, offset={}, size={})", ino, fh, offset, size);
+        self.serve(req, move |server, caller| {
+            server.read_file(caller, fh, offset, size, move |result| match result {
+                Ok(data) => reply.data(data),
+                Err(err) => reply.error(err),
+            })
+        });
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        debug!("opendir(ino={}, flags=0x{:x})", ino, flags);
+        self.serve(req, move |server, caller| server.netfuse.serve_opendir(&caller, ino, flags, reply));
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: u64, reply: ReplyDirectory) {
+        debug!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
+        self.serve(req, move |server, caller| server.netfuse.serve_readdir(&caller, ino, fh, offset, reply));
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        debug!("access(ino={}, mask={})", ino, mask);
+        self.serve(req, move |server, caller| server.netfuse.serve_access(&caller, ino, mask, reply));
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyData) {
+        debug!("getxattr(ino={}, name={:?})", ino, name);
+        let name = name.to_owned();
+        self.serve(req, move |server, caller| server.netfuse.serve_getxattr(&caller, ino, &name, reply));
+    }
+
+    fn setxattr(&mut self, req: &Request, ino: u64, name: &OsStr, _value: &[u8], flags: u32, _position: u32, reply: ReplyEmpty) {
+        debug!("setxattr(ino={}, name={:?}, flags=0x{:x})", ino, name, flags);
+        let name = name.to_owned();
+        self.serve(req, move |server, caller| server.netfuse.serve_setxattr(&caller, ino, &name, reply));
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr(ino={}, name={:?})", ino, name);
+        let name = name.to_owned();
+        self.serve(req, move |server, caller| server.netfuse.serve_removexattr(&caller, ino, &name, reply));
+    }
+
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        debug!("releasedir(ino={}, fh={}, flags=0x{:x})", ino, fh, flags);
+        self.send(move |server| server.netfuse.serve_releasedir(fh, reply));
+    }
+
+    fn mknod(&mut self, req: &Request, parent: u64, name: &Path, mode: u32, _rdev: u32, reply: ReplyEntry) {
+        debug!("mknod(parent={}, name={}, mode=0o{:o})", parent, name.display(), mode);
+        let name = name.to_owned();
+        self.serve(req, move |server, caller| server.netfuse.serve_mknod(&caller, parent, &name, mode, reply));
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &Path, mode: u32, reply: ReplyEntry) {
+        debug!("mkdir(parent={}, name={}, mode=0o{:o})", parent, name.display(), mode);
+        let name = name.to_owned();
+        self.serve(req, move |server, caller| server.netfuse.serve_mkdir(&caller, parent, &name, mode, reply));
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        debug!("open(ino={}, flags=0x{:x})", ino, flags);
+        self.serve(req, move |server, caller| server.netfuse.serve_open(&caller, ino, flags, reply));
+    }
+
+    fn flush(&mut self, req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        debug!("flush(ino={}, fh={})", ino, fh);
+        self.serve(req, move |server, caller| server.netfuse.serve_flush(&caller, ino, fh, reply));
+    }
+
+    fn release(&mut self, req: &Request, ino: u64, fh: u64, flags: u32, _lock_owner: u64, flush: bool, reply: ReplyEmpty) {
+        debug!("release(ino={}, fh={}, flags=0x{:x}, flush={})", ino, fh, flags, flush);
+        self.serve(req, move |server, caller| server.netfuse.serve_release(&caller, fh, reply));
+    }
+
+    fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        debug!("fsync(ino={}, fh={}, datasync={})", ino, fh, datasync);
+        self.serve(req, move |server, caller| server.netfuse.serve_fsync(&caller, fh, reply));
+    }
+
+    fn write(&mut self, req: &Request, ino: u64, fh: u64, offset: u64, data: &[u8], flags: u32, reply: ReplyWrite) {
+        debug!("write(ino={}, fh={}, offset={}, len={}, flags=0x{:x})", ino, fh, offset, data.len(), flags);
+        let data = data.to_vec();
+        self.serve(req, move |server, caller| server.netfuse.serve_write(&caller, ino, fh, offset, &data, reply));
+    }
+
+    fn setattr(&mut self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, flags: Option<u32>, reply: ReplyAttr) {
+        debug!("setattr(ino={}, mode={:?}, size={:?}, fh={:?}, flags={:?})", ino, mode, size, fh, flags);
+        self.serve(req, move |server, caller| match server.netfuse.set_attr(&caller, ino, uid, gid, size, fh) {
+            Ok(attr) => reply.attr(&DEFAULT_TTL, &attr),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &Path, reply: ReplyEmpty) {
+        debug!("rmdir(parent={}, name={})", parent, name.display());
+        let name = name.to_owned();
+        self.serve(req, move |server, caller| server.netfuse.serve_rmdir(&caller, parent, &name, reply));
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &Path, reply: ReplyEmpty) {
+        debug!("unlink(parent={}, name={})", parent, name.display());
+        let name = name.to_owned();
+        self.serve(req, move |server, caller| server.netfuse.serve_unlink(&caller, parent, &name, reply));
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuse::FileType;
+    use libc::O_RDONLY;
+    use local_metadata;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+    use std::time::Duration;
+    use {Metadata, NfsFuture};
+
+    // Backend whose reads and lookups only finish once two of them are running at the same time
+    #[derive(Default)]
+    struct RendezvousFs {
+        // number of calls that started, and the wakers of those still waiting
+        state: Mutex<(usize, Vec<Waker>)>,
+    }
+
+    // Future that resolves to `value` once two calls have started
+    struct Rendezvous<'a, T> {
+        state: &'a Mutex<(usize, Vec<Waker>)>,
+        started: bool,
+        value: Option<T>,
+    }
+
+    impl<'a, T: Unpin> Future for Rendezvous<'a, T> {
+        type Output = Result<T, LibcError>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            let shared = self.state;
+            let mut state = shared.lock().unwrap();
+            if !self.started {
+                self.started = true;
+                state.0 += 1;
+                for waker in state.1.drain(..) {
+                    waker.wake();
+                }
+            }
+            if state.0 < 2 {
+                state.1.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            Poll::Ready(Ok(self.value.take().unwrap()))
+        }
+    }
+
+    impl RendezvousFs {
+        fn rendezvous<T: Unpin + 'static>(&self, value: T) -> NfsFuture<'_, T> {
+            Box::pin(Rendezvous { state: &self.state, started: false, value: Some(value) })
+        }
+    }
+
+    impl AsyncNetworkFilesystem for RendezvousFs {
+        fn lookup<'a>(&'a self, _caller: &'a Caller, _path: &'a Path) -> NfsFuture<'a, Metadata> {
+            self.rendezvous(local_metadata(FileType::RegularFile, 0o644, 3))
+        }
+
+        fn read<'a>(&'a self, _caller: &'a Caller, _path: &'a Path) -> NfsFuture<'a, Vec<u8>> {
+            self.rendezvous(b"abc".to_vec())
+        }
+    }
+
+    #[test]
+    fn test_slow_requests_overlap() {
+        let options = MountOptions::new(&"/mnt");
+        let (jobs, queue) = mpsc::channel();
+        thread::scope(|scope| {
+            let server_jobs = jobs.clone();
+            let options = &options;
+            scope.spawn(move || serve(RendezvousFs::default(), options, server_jobs, queue));
+            let fuse = AsyncNetFuse::new(jobs, false);
+            let caller = Caller::new(1000, 1000, 42);
+            let opener = caller.clone();
+            let fh = fuse.call(move |server| {
+                let metadata = local_metadata(FileType::RegularFile, 0o644, 3);
+                let ino = server.netfuse.inodes.insert_metadata("/a.txt", &metadata).attr.ino;
+                server.netfuse.open_file(&opener, ino, O_RDONLY as u32)
+            });
+            let fh = fh.unwrap().unwrap();
+
+            // neither request can be answered before the other one reaches the backend
+            let (sender, answers) = mpsc::channel();
+            let (read_sender, reader) = (sender.clone(), caller.clone());
+            fuse.send(move |server| {
+                server.read_file(reader, fh, 0, 10, move |data| read_sender.send(data.map(|data| data.len() as u64)).unwrap())
+            });
+            fuse.send(move |server| {
+                server.lookup_child(caller, 1, Path::new("b.txt"), move |netfuse, ino| {
+                    sender.send(ino.map(|ino| netfuse.inodes[ino].attr.size)).unwrap()
+                })
+            });
+            for _ in 0..2 {
+                assert_eq!(answers.recv_timeout(Duration::from_secs(10)), Ok(Ok(3)));
+            }
+        });
+    }
+}
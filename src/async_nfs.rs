@@ -0,0 +1,550 @@
+use libc::ENOSYS;
+use std::future::{self, Future};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use super::{Caller, DirEntry, DirIterator, LibcError, LookupStrategy, Metadata, NetworkFilesystem, Revalidation};
+
+/// Boxed future returned by the methods of `AsyncNetworkFilesystem`
+pub type NfsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, LibcError>> + 'a>>;
+
+/// Directory listing returned by `AsyncNetworkFilesystem::readdir`, read one entry at a time
+///
+/// Every iterator over `Result<DirEntry, LibcError>` is a `DirStream` whose entries are ready right away.
+pub trait DirStream {
+    /// Resolves to the next entry, or to `None` once the listing is exhausted
+    ///
+    /// An error only fails this entry, as with the items of `DirIterator`, and later calls
+    ///   may still return entries.
+    fn next_entry(&mut self) -> NfsFuture<'_, Option<DirEntry>>;
+}
+
+impl<I: Iterator<Item = Result<DirEntry, LibcError>>> DirStream for I {
+    fn next_entry(&mut self) -> NfsFuture<'_, Option<DirEntry>> {
+        ready(self.next().transpose())
+    }
+}
+
+/// Futures-based counterpart of `NetworkFilesystem`
+///
+/// Each method mirrors the method of the same name on `NetworkFilesystem`,
+/// but returns a future instead of blocking. Methods take `&self`; use interior mutability for any state.
+///
+/// Each future is polled to completion by one thread, which is parked while the future is pending
+/// and unparked by its waker, so futures may be completed from any thread. `mount_async` waits for
+/// reads of uncached file data and lookups of uncached names on worker threads, so these overlap,
+/// and waits for other requests one at a time. Background work like prefetching runs on the handles
+/// from `clone_for_prefetch` and `clone_for_journal` in threads of its own. Futures that must be polled
+/// from inside a specific runtime (e.g. tokio I/O resources) should be spawned onto that
+/// runtime, returning a future that resolves to the spawned task's result.
+///
+/// Use `mount_async` to mount an implementation of this trait.
+pub trait AsyncNetworkFilesystem {
+    /// See `NetworkFilesystem::init`
+    fn init(&self) -> NfsFuture<'_, ()> {
+        ready(Ok(()))
+    }
+
+    /// See `NetworkFilesystem::destroy`
+    fn destroy(&self) -> NfsFuture<'_, ()> {
+        ready(Ok(()))
+    }
+
+    /// See `NetworkFilesystem::lookup_strategy`
+    fn lookup_strategy(&self) -> LookupStrategy {
+        LookupStrategy::Stat
+    }
+
+    /// See `NetworkFilesystem::isolate_users`
+    fn isolate_users(&self) -> bool {
+        false
+    }
+
+    /// See `NetworkFilesystem::clone_for_prefetch`
+    ///
+    /// The handle is used from a background thread, so a clone of the backend can be
+    ///   returned wrapped in an `AsyncAdapter`, which blocks that thread on its futures.
+    fn clone_for_prefetch(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+        None
+    }
+
+    /// See `NetworkFilesystem::clone_for_journal`
+    ///
+    /// As with `clone_for_prefetch`, a clone wrapped in an `AsyncAdapter` can be returned.
+    fn clone_for_journal(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+        None
+    }
+
+    /// See `NetworkFilesystem::lookup`
+    fn lookup<'a>(&'a self, _caller: &'a Caller, _path: &'a Path) -> NfsFuture<'a, Metadata> {
+        ready(Err(ENOSYS))
+    }
+
+    /// See `NetworkFilesystem::revalidate`
+    fn revalidate<'a>(&'a self, _caller: &'a Caller, _path: &'a Path, _version: &'a str) -> NfsFuture<'a, Revalidation> {
+        ready(Err(ENOSYS))
+    }
+
+    /// See `NetworkFilesystem::check_access`
+    fn check_access<'a>(&'a self, _caller: &'a Caller, _path: &'a Path, _mode: u32) -> NfsFuture<'a, ()> {
+        ready(Ok(()))
+    }
+
+    /// Resolves to the full contents of the file at `path`
+    ///
+    /// See `NetworkFilesystem::read`
+    fn read<'a>(&'a self, _caller: &'a Caller, _path: &'a Path) -> NfsFuture<'a, Vec<u8>> {
+        ready(Err(ENOSYS))
+    }
+
+    /// See `NetworkFilesystem::write`
+    fn write<'a>(&'a self, _caller: &'a Caller, _path: &'a Path, _data: &'a [u8], _version: Option<&'a str>) -> NfsFuture<'a, Option<String>> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Resolves to a listing of the directory at `path`, whose entries are pulled as `NetFuse` needs them
+    ///
+    /// See `NetworkFilesystem::readdir`
+    fn readdir<'a>(&'a self, _caller: &'a Caller, _path: &'a Path) -> NfsFuture<'a, Box<dyn DirStream>> {
+        ready(Err(ENOSYS))
+    }
+
+    /// See `NetworkFilesystem::mkdir`
+    fn mkdir<'a>(&'a self, _caller: &'a Caller, _path: &'a Path) -> NfsFuture<'a, ()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// See `NetworkFilesystem::chown`
+    fn chown<'a>(&'a self, _caller: &'a Caller, _path: &'a Path, _owner: Option<&'a str>, _group: Option<&'a str>) -> NfsFuture<'a, ()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// See `NetworkFilesystem::rmdir`
+    fn rmdir<'a>(&'a self, _caller: &'a Caller, _path: &'a Path) -> NfsFuture<'a, ()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// See `NetworkFilesystem::unlink`
+    fn unlink<'a>(&'a self, _caller: &'a Caller, _path: &'a Path) -> NfsFuture<'a, ()> {
+        ready(Err(ENOSYS))
+    }
+}
+
+/// Wraps an `AsyncNetworkFilesystem` so that `NetFuse` can drive it
+///
+/// Every `NetworkFilesystem` call made by `NetFuse` creates the corresponding future
+/// and polls it to completion, parking the calling thread whenever the future is pending.
+#[derive(Debug)]
+pub struct AsyncAdapter<A: AsyncNetworkFilesystem> {
+    inner: A,
+}
+
+impl<A: AsyncNetworkFilesystem> AsyncAdapter<A> {
+    pub fn new(fs: A) -> AsyncAdapter<A> {
+        AsyncAdapter { inner: fs }
+    }
+
+    /// Returns the wrapped `AsyncNetworkFilesystem`
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+}
+
+impl<A: AsyncNetworkFilesystem> NetworkFilesystem for AsyncAdapter<A> {
+    fn init(&mut self) -> Result<(), LibcError> {
+        block_on(self.inner.init())
+    }
+
+    fn destroy(&mut self) -> Result<(), LibcError> {
+        block_on(self.inner.destroy())
+    }
+
+    fn lookup_strategy(&self) -> LookupStrategy {
+        self.inner.lookup_strategy()
+    }
+
+    fn isolate_users(&self) -> bool {
+        self.inner.isolate_users()
+    }
+
+    fn clone_for_prefetch(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+        self.inner.clone_for_prefetch()
+    }
+
+    fn clone_for_journal(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+        self.inner.clone_for_journal()
+    }
+
+    fn lookup(&mut self, caller: &Caller, path: &Path) -> Result<Metadata, LibcError> {
+        block_on(self.inner.lookup(caller, path))
+    }
+
+    fn revalidate(&mut self, caller: &Caller, path: &Path, version: &str) -> Result<Revalidation, LibcError> {
+        block_on(self.inner.revalidate(caller, path, version))
+    }
+
+    fn check_access(&mut self, caller: &Caller, path: &Path, mode: u32) -> Result<(), LibcError> {
+        block_on(self.inner.check_access(caller, path, mode))
+    }
+
+    fn read(&mut self, caller: &Caller, path: &Path, buffer: &mut Vec<u8>) -> Result<usize, LibcError> {
+        let data = block_on(self.inner.read(caller, path))?;
+        buffer.extend_from_slice(&data);
+        Ok(data.len())
+    }
+
+    fn write(&mut self, caller: &Caller, path: &Path, data: &[u8], version: Option<&str>) -> Result<Option<String>, LibcError> {
+        block_on(self.inner.write(caller, path, data, version))
+    }
+
+    fn readdir(&mut self, caller: &Caller, path: &Path) -> DirIterator {
+        match block_on(self.inner.readdir(caller, path)) {
+            Ok(stream) => Box::new(StreamIterator { stream: stream }),
+            Err(err) => Box::new(Some(Err(err)).into_iter()),
+        }
+    }
+
+    fn mkdir(&mut self, caller: &Caller, path: &Path) -> Result<(), LibcError> {
+        block_on(self.inner.mkdir(caller, path))
+    }
+
+    fn chown(&mut self, caller: &Caller, path: &Path, owner: Option<&str>, group: Option<&str>) -> Result<(), LibcError> {
+        block_on(self.inner.chown(caller, path, owner, group))
+    }
+
+    fn rmdir(&mut self, caller: &Caller, path: &Path) -> Result<(), LibcError> {
+        block_on(self.inner.rmdir(caller, path))
+    }
+
+    fn unlink(&mut self, caller: &Caller, path: &Path) -> Result<(), LibcError> {
+        block_on(self.inner.unlink(caller, path))
+    }
+}
+
+/// Blanket adapter that exposes any `NetworkFilesystem` as an `AsyncNetworkFilesystem`
+///
+/// Calls are serialized through the mutex and resolve immediately.
+impl<NFS: NetworkFilesystem> AsyncNetworkFilesystem for Mutex<NFS> {
+    fn init(&self) -> NfsFuture<'_, ()> {
+        ready(self.lock().unwrap().init())
+    }
+
+    fn destroy(&self) -> NfsFuture<'_, ()> {
+        ready(self.lock().unwrap().destroy())
+    }
+
+    fn lookup_strategy(&self) -> LookupStrategy {
+        self.lock().unwrap().lookup_strategy()
+    }
+
+    fn isolate_users(&self) -> bool {
+        self.lock().unwrap().isolate_users()
+    }
+
+    fn clone_for_prefetch(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+        self.lock().unwrap().clone_for_prefetch()
+    }
+
+    fn clone_for_journal(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+        self.lock().unwrap().clone_for_journal()
+    }
+
+    fn lookup<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, Metadata> {
+        ready(self.lock().unwrap().lookup(caller, path))
+    }
+
+    fn revalidate<'a>(&'a self, caller: &'a Caller, path: &'a Path, version: &'a str) -> NfsFuture<'a, Revalidation> {
+        ready(self.lock().unwrap().revalidate(caller, path, version))
+    }
+
+    fn check_access<'a>(&'a self, caller: &'a Caller, path: &'a Path, mode: u32) -> NfsFuture<'a, ()> {
+        ready(self.lock().unwrap().check_access(caller, path, mode))
+    }
+
+    fn read<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, Vec<u8>> {
+        let mut buffer = Vec::new();
+        ready(self.lock().unwrap().read(caller, path, &mut buffer).map(|_| buffer))
+    }
+
+    fn write<'a>(&'a self, caller: &'a Caller, path: &'a Path, data: &'a [u8], version: Option<&'a str>) -> NfsFuture<'a, Option<String>> {
+        ready(self.lock().unwrap().write(caller, path, data, version))
+    }
+
+    fn readdir<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, Box<dyn DirStream>> {
+        ready(Ok(Box::new(self.lock().unwrap().readdir(caller, path))))
+    }
+
+    fn mkdir<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, ()> {
+        ready(self.lock().unwrap().mkdir(caller, path))
+    }
+
+    fn chown<'a>(&'a self, caller: &'a Caller, path: &'a Path, owner: Option<&'a str>, group: Option<&'a str>) -> NfsFuture<'a, ()> {
+        ready(self.lock().unwrap().chown(caller, path, owner, group))
+    }
+
+    fn rmdir<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, ()> {
+        ready(self.lock().unwrap().rmdir(caller, path))
+    }
+
+    fn unlink<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, ()> {
+        ready(self.lock().unwrap().unlink(caller, path))
+    }
+}
+
+/// Shares an `AsyncNetworkFilesystem` between owners, e.g. `NetFuse` and the workers of `mount_async`
+impl<A: AsyncNetworkFilesystem + ?Sized> AsyncNetworkFilesystem for Arc<A> {
+    fn init(&self) -> NfsFuture<'_, ()> {
+        (**self).init()
+    }
+
+    fn destroy(&self) -> NfsFuture<'_, ()> {
+        (**self).destroy()
+    }
+
+    fn lookup_strategy(&self) -> LookupStrategy {
+        (**self).lookup_strategy()
+    }
+
+    fn isolate_users(&self) -> bool {
+        (**self).isolate_users()
+    }
+
+    fn clone_for_prefetch(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+        (**self).clone_for_prefetch()
+    }
+
+    fn clone_for_journal(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+        (**self).clone_for_journal()
+    }
+
+    fn lookup<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, Metadata> {
+        (**self).lookup(caller, path)
+    }
+
+    fn revalidate<'a>(&'a self, caller: &'a Caller, path: &'a Path, version: &'a str) -> NfsFuture<'a, Revalidation> {
+        (**self).revalidate(caller, path, version)
+    }
+
+    fn check_access<'a>(&'a self, caller: &'a Caller, path: &'a Path, mode: u32) -> NfsFuture<'a, ()> {
+        (**self).check_access(caller, path, mode)
+    }
+
+    fn read<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, Vec<u8>> {
+        (**self).read(caller, path)
+    }
+
+    fn write<'a>(&'a self, caller: &'a Caller, path: &'a Path, data: &'a [u8], version: Option<&'a str>) -> NfsFuture<'a, Option<String>> {
+        (**self).write(caller, path, data, version)
+    }
+
+    fn readdir<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, Box<dyn DirStream>> {
+        (**self).readdir(caller, path)
+    }
+
+    fn mkdir<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, ()> {
+        (**self).mkdir(caller, path)
+    }
+
+    fn chown<'a>(&'a self, caller: &'a Caller, path: &'a Path, owner: Option<&'a str>, group: Option<&'a str>) -> NfsFuture<'a, ()> {
+        (**self).chown(caller, path, owner, group)
+    }
+
+    fn rmdir<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, ()> {
+        (**self).rmdir(caller, path)
+    }
+
+    fn unlink<'a>(&'a self, caller: &'a Caller, path: &'a Path) -> NfsFuture<'a, ()> {
+        (**self).unlink(caller, path)
+    }
+}
+
+// Blocks on each entry of a `DirStream` as it is pulled
+struct StreamIterator {
+    stream: Box<dyn DirStream>,
+}
+
+impl Iterator for StreamIterator {
+    type Item = Result<DirEntry, LibcError>;
+
+    fn next(&mut self) -> Option<Result<DirEntry, LibcError>> {
+        block_on(self.stream.next_entry()).transpose()
+    }
+}
+
+fn ready<'a, T: 'a>(result: Result<T, LibcError>) -> NfsFuture<'a, T> {
+    Box::pin(future::ready(result))
+}
+
+// Waker that unparks the thread blocked in `block_on`
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// Polls the future on the current thread until it resolves
+pub(crate) fn block_on<T>(mut future: NfsFuture<T>) -> Result<T, LibcError> {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(result) => return result,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll};
+    use std::thread;
+    use std::time::Duration;
+    use libc::ENOENT;
+
+    // Future that resolves only after another thread has woken it
+    struct Delayed {
+        started: bool,
+        done: Arc<Mutex<bool>>,
+    }
+
+    impl Future for Delayed {
+        type Output = Result<(), LibcError>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            if *self.done.lock().unwrap() {
+                return Poll::Ready(Ok(()));
+            }
+            if !self.started {
+                self.started = true;
+                let done = self.done.clone();
+                let waker = cx.waker().clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(10));
+                    *done.lock().unwrap() = true;
+                    waker.wake();
+                });
+            }
+            Poll::Pending
+        }
+    }
+
+    #[derive(Clone)]
+    struct DelayedFs;
+
+    impl AsyncNetworkFilesystem for DelayedFs {
+        fn clone_for_prefetch(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+            Some(Box::new(AsyncAdapter::new(self.clone())))
+        }
+
+        fn mkdir<'a>(&'a self, _caller: &'a Caller, _path: &'a Path) -> NfsFuture<'a, ()> {
+            Box::pin(Delayed { started: false, done: Arc::new(Mutex::new(false)) })
+        }
+    }
+
+    struct SyncFs;
+
+    impl NetworkFilesystem for SyncFs {
+        fn read(&mut self, _caller: &Caller, _path: &Path, buffer: &mut Vec<u8>) -> Result<usize, LibcError> {
+            buffer.extend_from_slice(b"hello");
+            Ok(5)
+        }
+        fn unlink(&mut self, _caller: &Caller, _path: &Path) -> Result<(), LibcError> {
+            Err(ENOENT)
+        }
+    }
+
+    #[test]
+    fn test_async_adapter_waits_for_wakeup() {
+        let caller = Caller::new(1000, 1000, 1);
+        let mut adapter = AsyncAdapter::new(DelayedFs);
+        assert_eq!(adapter.mkdir(&caller, Path::new("/data")), Ok(()));
+        assert_eq!(adapter.rmdir(&caller, Path::new("/data")), Err(ENOSYS));
+    }
+
+    #[test]
+    fn test_async_adapter_clones() {
+        let caller = Caller::new(1000, 1000, 1);
+        let adapter = AsyncAdapter::new(DelayedFs);
+        assert!(adapter.clone_for_journal().is_none());
+        let mut clone = adapter.clone_for_prefetch().unwrap();
+        thread::spawn(move || clone.mkdir(&caller, Path::new("/data"))).join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_async_adapter_over_sync_fs() {
+        let caller = Caller::new(1000, 1000, 1);
+        let mut adapter = AsyncAdapter::new(Mutex::new(SyncFs));
+        let mut buffer = Vec::new();
+        assert_eq!(adapter.read(&caller, Path::new("/foo.txt"), &mut buffer), Ok(5));
+        assert_eq!(&buffer, b"hello");
+        assert_eq!(adapter.unlink(&caller, Path::new("/foo.txt")), Err(ENOENT));
+        assert_eq!(adapter.readdir(&caller, Path::new("/")).collect::<Vec<_>>(), vec![Err(ENOSYS)]);
+    }
+
+    // Listing of `total` files that counts how many entries were pulled
+    struct CountingStream {
+        pulled: Rc<Cell<usize>>,
+        total: usize,
+    }
+
+    impl DirStream for CountingStream {
+        fn next_entry(&mut self) -> NfsFuture<'_, Option<DirEntry>> {
+            let pulled = self.pulled.get();
+            if pulled == self.total {
+                return ready(Ok(None));
+            }
+            self.pulled.set(pulled + 1);
+            let now = ::time::now_utc().to_timespec();
+            let metadata = Metadata {
+                size: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: ::fuse::FileType::RegularFile,
+                perm: 0o644,
+                version: None,
+                id: None,
+                blocks: None,
+                owner: None,
+                group: None,
+            };
+            ready(Ok(Some(DirEntry::new(format!("{}.txt", pulled), metadata))))
+        }
+    }
+
+    struct ListingFs {
+        pulled: Rc<Cell<usize>>,
+    }
+
+    impl AsyncNetworkFilesystem for ListingFs {
+        fn readdir<'a>(&'a self, _caller: &'a Caller, _path: &'a Path) -> NfsFuture<'a, Box<dyn DirStream>> {
+            ready(Ok(Box::new(CountingStream { pulled: self.pulled.clone(), total: 3 })))
+        }
+    }
+
+    #[test]
+    fn test_async_adapter_streams_listing() {
+        let caller = Caller::new(1000, 1000, 1);
+        let pulled = Rc::new(Cell::new(0));
+        let mut adapter = AsyncAdapter::new(ListingFs { pulled: pulled.clone() });
+        let mut entries = adapter.readdir(&caller, Path::new("/"));
+        assert_eq!(pulled.get(), 0);
+        assert_eq!(entries.next().unwrap().unwrap().filename, "0.txt");
+        assert_eq!(pulled.get(), 1);
+        assert_eq!(entries.count(), 2);
+    }
+}
@@ -6,6 +6,8 @@ pub struct CacheEntry {
     pub warm: bool,
     // Indicates if the data is in sync with the API (false implies we should persists)
     pub sync: bool,
+    // Backend version the data was read from, checked when writing it back
+    pub version: Option<String>,
     // Number of open handles to this CacheEntry
     handles: u32,
 }
@@ -16,6 +18,7 @@ impl CacheEntry {
             data: Vec::new(),
             warm: false,
             sync: false,
+            version: None,
             handles: 0,
         }
     }
@@ -26,11 +29,21 @@ impl CacheEntry {
         self.data = data.into();
     }
 
+    // Drops the cached data so that it is read again from the API, keeping open handles
+    pub fn discard(&mut self) {
+        self.sync = false;
+        self.warm = false;
+        self.data = Vec::new();
+        self.version = None;
+    }
+
     pub fn write(&mut self, offset: u64, data: &[u8]) {
         self.sync = false;
         self.warm = true;
         let end = offset as usize + data.len();
-        self.data.resize(end, 0);
+        if end > self.data.len() {
+            self.data.resize(end, 0);
+        }
         debug!("write(offset={}, data.len={}, end={})", offset, data.len(), end);
         self.data[(offset as usize)..end].copy_from_slice(data);
     }
@@ -40,6 +53,10 @@ impl CacheEntry {
         self.handles
     }
 
+    pub fn is_open(&self) -> bool {
+        self.handles > 0
+    }
+
     pub fn opened(&mut self) -> u32 {
         self.handles = self.handles + 1;
         self.handles
@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+use super::{LibcError, is_unreachable};
+
+/// Options for serving cached data while the backend is unreachable
+///
+/// The backend is considered offline after `failures` calls in a row failed with an error that
+/// `is_unreachable` accepts. While offline, lookups, directory listings and reads are answered
+/// from the inode store and data cache without calling the backend, and fail with `EIO` only
+/// for what was never cached. Writes are still attempted. After each `probe_interval`, the next
+/// call is let through to the backend as a probe, and any answer from it brings the backend back online.
+#[derive(Debug, Copy, Clone)]
+pub struct OfflineOptions {
+    /// Number of consecutive network failures after which the backend is considered offline
+    pub failures: u32,
+    /// Time between probes while offline
+    pub probe_interval: Duration,
+    /// Maximum bytes of clean data kept in memory after files are closed, to be read while offline
+    ///
+    /// Data of the files closed longest ago is dropped first once over the limit.
+    pub max_bytes: u64,
+}
+
+impl Default for OfflineOptions {
+    fn default() -> OfflineOptions {
+        OfflineOptions {
+            failures: 3,
+            probe_interval: Duration::from_secs(30),
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+// Reachability of the backend
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BackendHealth {
+    // calls are succeeding
+    Online,
+    // recent calls couldn't reach the backend, but not enough of them to go offline
+    Degraded,
+    // calls are skipped, except for probes
+    Offline,
+}
+
+// Tracks backend health from the outcome of calls
+#[derive(Debug)]
+pub struct Health {
+    options: OfflineOptions,
+    state: BackendHealth,
+    // consecutive calls that couldn't reach the backend
+    failures: u32,
+    // when the last probe was let through, or when the backend went offline
+    last_probe: Instant,
+}
+
+impl Health {
+    pub fn new(options: OfflineOptions) -> Health {
+        Health {
+            options: options,
+            state: BackendHealth::Online,
+            failures: 0,
+            last_probe: Instant::now(),
+        }
+    }
+
+    pub fn options(&self) -> &OfflineOptions {
+        &self.options
+    }
+
+    // true if a call should be made to the backend: always unless offline,
+    // and then only once per probe interval
+    pub fn should_try(&mut self) -> bool {
+        if self.state != BackendHealth::Offline {
+            return true;
+        }
+        if self.last_probe.elapsed() < self.options.probe_interval {
+            return false;
+        }
+        debug!("probing offline backend");
+        self.last_probe = Instant::now();
+        true
+    }
+
+    // Records the error of a call, or None if it succeeded. Errors other than network
+    // failures still mean the backend answered.
+    pub fn record(&mut self, err: Option<LibcError>) {
+        match err {
+            Some(err) if is_unreachable(err) => {
+                self.failures += 1;
+                match self.state {
+                    BackendHealth::Online => {
+                        warn!("backend degraded - {}", err);
+                        self.state = BackendHealth::Degraded;
+                    }
+                    BackendHealth::Degraded => (),
+                    BackendHealth::Offline => info!("backend still offline - {}", err),
+                }
+                if self.state == BackendHealth::Degraded && self.failures >= self.options.failures {
+                    warn!("backend offline after {} failed calls, serving from cache", self.failures);
+                    self.state = BackendHealth::Offline;
+                    self.last_probe = Instant::now();
+                }
+            }
+            _ => {
+                if self.state != BackendHealth::Online {
+                    info!("backend online again");
+                }
+                self.state = BackendHealth::Online;
+                self.failures = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::{ECONNREFUSED, ENOENT, ETIMEDOUT};
+
+    #[test]
+    fn test_health_transitions() {
+        let mut health = Health::new(OfflineOptions { failures: 2, probe_interval: Duration::from_secs(60), ..OfflineOptions::default() });
+        health.record(Some(ETIMEDOUT));
+        assert_eq!(health.state, BackendHealth::Degraded);
+        health.record(None);
+        assert_eq!(health.state, BackendHealth::Online);
+
+        // Only network failures in a row take the backend offline
+        health.record(Some(ETIMEDOUT));
+        health.record(Some(ENOENT));
+        health.record(Some(ECONNREFUSED));
+        assert_eq!(health.state, BackendHealth::Degraded);
+        assert!(health.should_try());
+        health.record(Some(ECONNREFUSED));
+        assert_eq!(health.state, BackendHealth::Offline);
+        assert!(!health.should_try());
+
+        // Probes are let through once the interval is over
+        health.options.probe_interval = Duration::from_secs(0);
+        assert!(health.should_try());
+        health.record(Some(ECONNREFUSED));
+        assert_eq!(health.state, BackendHealth::Offline);
+        assert!(health.should_try());
+        health.record(Some(ENOENT));
+        assert_eq!(health.state, BackendHealth::Online);
+    }
+}
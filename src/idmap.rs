@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+/// Maps the owners reported in `Metadata` to local user and group ids
+///
+/// Set with `MountOptions::id_map`. Remote users and groups without a mapping get the fallback
+/// ids if set, and otherwise the ids the volume is mounted with. Changing the owner of a file
+/// maps the new local ids back to remote names, so only mapped ids can be given to files.
+#[derive(Debug, Clone, Default)]
+pub struct IdMap {
+    users: HashMap<String, u32>,
+    groups: HashMap<String, u32>,
+    fallback_uid: Option<u32>,
+    fallback_gid: Option<u32>,
+}
+
+impl IdMap {
+    pub fn new() -> IdMap {
+        IdMap::default()
+    }
+
+    /// Maps the remote user `name` to the local `uid`
+    pub fn user<S: Into<String>>(mut self, name: S, uid: u32) -> IdMap {
+        self.users.insert(name.into(), uid);
+        self
+    }
+
+    /// Maps the remote group `name` to the local `gid`
+    pub fn group<S: Into<String>>(mut self, name: S, gid: u32) -> IdMap {
+        self.groups.insert(name.into(), gid);
+        self
+    }
+
+    /// Sets the uid of remote users that have no mapping
+    pub fn fallback_uid(mut self, uid: u32) -> IdMap {
+        self.fallback_uid = Some(uid);
+        self
+    }
+
+    /// Sets the gid of remote groups that have no mapping
+    pub fn fallback_gid(mut self, gid: u32) -> IdMap {
+        self.fallback_gid = Some(gid);
+        self
+    }
+
+    /// Local uid of the remote user `name`, or the fallback uid if it has no mapping
+    pub fn uid_for_user(&self, name: &str) -> Option<u32> {
+        self.users.get(name).cloned().or(self.fallback_uid)
+    }
+
+    /// Local gid of the remote group `name`, or the fallback gid if it has no mapping
+    pub fn gid_for_group(&self, name: &str) -> Option<u32> {
+        self.groups.get(name).cloned().or(self.fallback_gid)
+    }
+
+    /// Remote user mapped to `uid`
+    ///
+    /// If several users map to `uid`, the first by name is returned.
+    pub fn user_for_uid(&self, uid: u32) -> Option<&str> {
+        reverse(&self.users, uid)
+    }
+
+    /// Remote group mapped to `gid`
+    ///
+    /// If several groups map to `gid`, the first by name is returned.
+    pub fn group_for_gid(&self, gid: u32) -> Option<&str> {
+        reverse(&self.groups, gid)
+    }
+}
+
+fn reverse(map: &HashMap<String, u32>, id: u32) -> Option<&str> {
+    map.iter()
+        .filter(|&(_, mapped)| *mapped == id)
+        .map(|(name, _)| name.as_str())
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_map() {
+        let map = IdMap::new()
+            .user("alice", 1000)
+            .user("alice@example.com", 1000)
+            .user("bob", 1001)
+            .group("staff", 50)
+            .fallback_uid(65534);
+
+        assert_eq!(map.uid_for_user("bob"), Some(1001));
+        assert_eq!(map.uid_for_user("mallory"), Some(65534));
+        assert_eq!(map.gid_for_group("staff"), Some(50));
+        assert_eq!(map.gid_for_group("wheel"), None);
+
+        assert_eq!(map.user_for_uid(1000), Some("alice"));
+        assert_eq!(map.user_for_uid(65534), None);
+        assert_eq!(map.group_for_gid(50), Some("staff"));
+    }
+}
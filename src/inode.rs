@@ -1,17 +1,45 @@
 use fuse::{FileType, FileAttr};
 use sequence_trie::SequenceTrie;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ops::{Index, IndexMut};
 use time;
 use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
-use super::Metadata;
+use std::time::{Duration, Instant};
+use super::{IdMap, Metadata};
+
+// Number of negative entries that triggers purging the expired ones
+const NEGATIVE_PURGE_THRESHOLD: usize = 4096;
+
+// Header line identifying an inode state file
+const STATE_HEADER: &str = "netfuse-inodes 2";
+// Header of state files written before generations were persisted
+const STATE_HEADER_V1: &str = "netfuse-inodes 1";
 
 #[derive(Debug, Clone)]
 pub struct Inode {
     pub path: PathBuf,
     pub attr: FileAttr,
     pub visited: bool,
+    // Stable position of this inode within its parent's directory listing
+    pub cookie: u64,
+    // Backend version of the metadata (and data) last seen for this inode
+    pub version: Option<String>,
+    // When the metadata was last fetched or revalidated
+    pub validated: Instant,
+    // Backend identifier of the object, shared by every path that reaches it
+    pub id: Option<u64>,
+    // Paths other than `path` that resolve to this inode
+    pub aliases: Vec<PathBuf>,
+    // Number of references the kernel holds from lookups, released by `forget`
+    pub lookups: u64,
+    // Distinguishes this inode from earlier inodes that had the same inode number
+    pub generation: u64,
+    // Users the backend has shown this inode to, tracked when users are isolated
+    pub users: HashSet<u32>,
 }
 
 impl Inode {
@@ -20,17 +48,51 @@ impl Inode {
             path: PathBuf::from(path.as_ref()),
             attr: attr,
             visited: false,
+            cookie: 0,
+            version: None,
+            validated: Instant::now(),
+            id: None,
+            aliases: Vec::new(),
+            lookups: 0,
+            generation: 0,
+            users: HashSet::new(),
         }
     }
+
+    // Sets the file size along with the number of blocks it occupies
+    pub fn set_size(&mut self, size: u64) {
+        self.attr.size = size;
+        self.attr.blocks = blocks_for_size(size);
+    }
 }
 
 #[derive(Debug)]
 pub struct InodeStore {
     inode_map: HashMap<u64, Inode>,
     ino_trie: SequenceTrie<OsString, u64>,
+    // entries of each directory in the trie, by the path of the directory, ordered by cookie
+    listings: HashMap<Vec<OsString>, BTreeMap<(u64, OsString), u64>>,
     uid: u32,
     gid: u32,
+    // maps backend owners to local ids, if configured
+    id_map: Option<IdMap>,
     last_ino: u64,
+    last_cookie: u64,
+    // generation given to new inodes, incremented whenever an inode number is released
+    generation: u64,
+    // paths known not to exist, with the time until which that is assumed
+    negative: HashMap<PathBuf, Instant>,
+    // inode numbers and generations loaded from the state file for paths that have not been looked up yet
+    known: HashMap<PathBuf, (u64, u64)>,
+    // inode numbers of `known`, which new paths don't get
+    reserved: HashSet<u64>,
+    // map of backend object ids to inode numbers
+    ids: HashMap<u64, u64>,
+    // file the path to inode number mapping is persisted to
+    state_file: Option<PathBuf>,
+    // true if the mapping changed since it was last persisted
+    state_dirty: bool,
+    state_saved: Instant,
 }
 
 impl InodeStore {
@@ -38,9 +100,20 @@ impl InodeStore {
         let mut store = InodeStore {
             inode_map: HashMap::new(),
             ino_trie: SequenceTrie::new(),
+            listings: HashMap::new(),
             uid: uid,
             gid: gid,
+            id_map: None,
             last_ino: 1, // 1 is reserved for root
+            last_cookie: 2, // 1 and 2 are reserved for "." and ".."
+            generation: 0,
+            negative: HashMap::new(),
+            known: HashMap::new(),
+            reserved: HashSet::new(),
+            ids: HashMap::new(),
+            state_file: None,
+            state_dirty: false,
+            state_saved: Instant::now(),
         };
 
         let now = time::now_utc().to_timespec();
@@ -54,7 +127,7 @@ impl InodeStore {
             crtime: now,
             kind: FileType::Directory,
             perm: perm,
-            nlink: 0,
+            nlink: 1,
             uid: uid,
             gid: gid,
             rdev: 0,
@@ -74,39 +147,202 @@ impl InodeStore {
         self.ino_trie.get(&sequence).and_then(|ino| self.get(*ino))
     }
 
+    // Inode numbers are kept for known paths. Metadata with a backend `id` keeps the inode number
+    // of that object even if it is found at another path, and otherwise derives the inode number
+    // from the id unless that number is reserved or taken.
     pub fn insert_metadata<P: AsRef<Path>>(&mut self, path: P, metadata: &Metadata) -> &Inode {
         // Non-lexical borrows can't come soon enough
-        let ino_opt = self.get_by_path(path.as_ref())
-            .map(|inode| inode.attr.ino );
-        let ino = ino_opt.unwrap_or_else(|| {
-            self.last_ino += 1;
-            self.last_ino
-        });
+        let existing = self.get_by_path(path.as_ref())
+            .map(|inode| (inode.attr.ino, inode.id));
 
+        let ino = match metadata.id {
+            Some(id) => match self.ids.get(&id).cloned() {
+                Some(ino) => {
+                    if self.inode_map[&ino].path != path.as_ref() {
+                        self.link(ino, path.as_ref());
+                    }
+                    ino
+                }
+                None => match existing {
+                    // the path now holds a different object
+                    Some((old_ino, Some(_))) => {
+                        self.remove_tree(old_ino);
+                        self.ino_for_id(id)
+                    }
+                    Some((ino, None)) => ino,
+                    None => self.known_ino(path.as_ref()).unwrap_or_else(|| self.ino_for_id(id)),
+                },
+            },
+            None => existing.map(|(ino, _)| ino)
+                .or_else(|| self.known_ino(path.as_ref()))
+                .unwrap_or_else(|| self.next_ino()),
+        };
 
         debug!("insert metadata: {} {}", ino, path.as_ref().display());
 
         let attr = FileAttr {
             ino: ino,
             size: metadata.size,
-            blocks: 0,
+            blocks: metadata.blocks.unwrap_or_else(|| blocks_for_size(metadata.size)),
             atime: metadata.atime,
             mtime: metadata.mtime,
             ctime: metadata.ctime,
             crtime: metadata.crtime,
             kind: metadata.kind,
             perm: metadata.perm,
-            nlink: 0,
-            uid: self.uid,
-            gid: self.gid,
+            nlink: 1,
+            uid: self.owner_uid(metadata.owner.as_deref()),
+            gid: self.owner_gid(metadata.group.as_deref()),
             rdev: 0,
             flags: 0,
         };
 
-        self.insert(Inode::new(path, attr));
+        let mut inode = Inode::new(path, attr);
+        inode.version = metadata.version.clone();
+        inode.id = metadata.id;
+        self.insert(inode);
         self.get(ino).unwrap()
     }
 
+    pub fn id_map(&self) -> Option<&IdMap> {
+        self.id_map.as_ref()
+    }
+
+    // Maps backend owners of inserted metadata to local ids
+    pub fn set_id_map(&mut self, id_map: IdMap) {
+        self.id_map = Some(id_map);
+    }
+
+    fn owner_uid(&self, owner: Option<&str>) -> u32 {
+        match (self.id_map.as_ref(), owner) {
+            (Some(id_map), Some(owner)) => id_map.uid_for_user(owner).unwrap_or(self.uid),
+            _ => self.uid,
+        }
+    }
+
+    fn owner_gid(&self, group: Option<&str>) -> u32 {
+        match (self.id_map.as_ref(), group) {
+            (Some(id_map), Some(group)) => id_map.gid_for_group(group).unwrap_or(self.gid),
+            _ => self.gid,
+        }
+    }
+
+    // Next sequential inode number that is neither in use nor reserved for a known path
+    fn next_ino(&mut self) -> u64 {
+        loop {
+            self.last_ino += 1;
+            if !self.is_taken(self.last_ino) {
+                return self.last_ino;
+            }
+        }
+    }
+
+    // Inode number for a new backend object id: the id itself, unless it is reserved or taken
+    fn ino_for_id(&mut self, id: u64) -> u64 {
+        match id {
+            0 | 1 => self.next_ino(),
+            id if self.is_taken(id) => {
+                debug!("inode number {} for object id already taken", id);
+                self.next_ino()
+            }
+            id => id,
+        }
+    }
+
+    fn is_taken(&self, ino: u64) -> bool {
+        self.inode_map.contains_key(&ino) || self.reserved.contains(&ino)
+    }
+
+    // Keeps `ino` and `generation` for `path` until it is inserted again
+    fn reserve(&mut self, path: PathBuf, ino: u64, generation: u64) {
+        if let Some((old_ino, _)) = self.known.insert(path, (ino, generation)) {
+            self.reserved.remove(&old_ino);
+        }
+        self.reserved.insert(ino);
+    }
+
+    // Inode number loaded from the state file for `path`, if it hasn't been taken since
+    fn known_ino(&self, path: &Path) -> Option<u64> {
+        self.known.get(path).map(|&(ino, _)| ino).filter(|ino| !self.inode_map.contains_key(ino))
+    }
+
+    // Makes `path` the primary path of `ino`, keeping its previous path as an alias
+    // in case the object is still reachable there. Anything else cached at `path` is replaced.
+    // The cached children of a directory move along with it.
+    fn link(&mut self, ino: u64, path: &Path) {
+        let sequence = path_to_sequence(path);
+        if let Some(other) = self.ino_trie.get(&sequence).cloned() {
+            if other != ino {
+                self.remove_tree(other);
+            }
+        }
+
+        debug!("linking ino {} to {}", ino, path.display());
+        let inode = self.inode_map.get_mut(&ino).unwrap();
+        let old_path = ::std::mem::replace(&mut inode.path, path.to_owned());
+        inode.aliases.retain(|alias| alias != path);
+        inode.aliases.push(old_path.clone());
+        let is_dir = inode.attr.kind == FileType::Directory;
+        if !is_dir {
+            inode.attr.nlink = 1 + inode.aliases.len() as u32;
+        }
+        self.trie_insert(&sequence, ino);
+        if is_dir {
+            self.move_descendants(&old_path, path);
+        }
+        self.state_dirty = true;
+    }
+
+    // Drops the aliases among the children of the directory `ino` whose names are missing from
+    // `listed`, a complete listing of it, since the objects aren't reachable there anymore
+    // (e.g. after a rename on the backend)
+    pub fn drop_unlisted_aliases(&mut self, ino: u64, listed: &HashSet<OsString>) {
+        let dir = match self.get(ino) {
+            Some(inode) => inode.path.clone(),
+            None => return,
+        };
+        let stale: Vec<(PathBuf, u64)> = self.children_after(ino, 0)
+            .filter(|&(name, child)| !listed.contains(name) && child.path != dir.join(name))
+            .map(|(name, child)| (dir.join(name), child.attr.ino))
+            .collect();
+
+        for (path, child) in stale {
+            debug!("dropping alias {} of ino {}", path.display(), child);
+            self.trie_remove(&path_to_sequence(&path));
+            let inode = self.inode_map.get_mut(&child).unwrap();
+            inode.aliases.retain(|alias| alias != &path);
+            if inode.attr.kind != FileType::Directory {
+                inode.attr.nlink = 1 + inode.aliases.len() as u32;
+            }
+        }
+    }
+
+    // Moves the inodes cached beneath `from` to the same place beneath `to`
+    fn move_descendants(&mut self, from: &Path, to: &Path) {
+        let from_sequence = path_to_sequence(from);
+        let descendants: Vec<(PathBuf, u64)> = match self.ino_trie.get_node(&from_sequence) {
+            Some(node) => node.iter()
+                .filter(|(relative, _)| !relative.is_empty())
+                .map(|(relative, ino)| (relative.into_iter().collect(), *ino))
+                .collect(),
+            None => return,
+        };
+
+        for (relative, ino) in descendants {
+            debug!("moving ino {} from {} to {}", ino, from.join(&relative).display(), to.join(&relative).display());
+            self.trie_remove(&path_to_sequence(&from.join(&relative)));
+            self.trie_insert(&path_to_sequence(&to.join(&relative)), ino);
+
+            // the descendant may be an alias of an inode whose primary path is elsewhere
+            let inode = self.inode_map.get_mut(&ino).unwrap();
+            for path in ::std::iter::once(&mut inode.path).chain(inode.aliases.iter_mut()) {
+                if let Ok(rest) = path.strip_prefix(from).map(|rest| rest.to_owned()) {
+                    *path = to.join(rest);
+                }
+            }
+        }
+    }
+
     pub fn child<S: AsRef<OsStr>>(&self, ino: u64, name: S) -> Option<&Inode> {
         self.get(ino)
             .and_then(|inode| {
@@ -117,19 +353,22 @@ impl InodeStore {
     }
 
     pub fn children(&self, ino: u64) -> Vec<&Inode> {
-        match self.get(ino) {
-            Some(inode) => {
-                let sequence = path_to_sequence(&inode.path);
-                let node = self.ino_trie.get_node(&sequence)
-                    .expect("inconsistent fs - failed to lookup by path after lookup by ino");
-                node.children
-                    .values()
-                    .filter_map(|ref c| c.value.as_ref() )
-                    .map(|ino| self.get(*ino).expect("inconsistent fs - found child without inode") )
-                    .collect()
-            }
-            None => vec![],
-        }
+        self.named_children(ino).into_iter().map(|(_, child)| child).collect()
+    }
+
+    // Children with the name they have in this directory (which differs from
+    // the basename of their path for aliases), ordered by their directory cookie
+    pub fn named_children(&self, ino: u64) -> Vec<(&OsStr, &Inode)> {
+        self.children_after(ino, 0).collect()
+    }
+
+    // Children listed after the entry with the given directory cookie
+    pub fn children_after<'a>(&'a self, ino: u64, cookie: u64) -> impl Iterator<Item = (&'a OsStr, &'a Inode)> + 'a {
+        self.get(ino)
+            .and_then(|inode| self.listings.get(&path_to_sequence(&inode.path)))
+            .into_iter()
+            .flat_map(move |listing| listing.range((cookie.saturating_add(1), OsString::new())..))
+            .map(move |(key, ino)| (key.1.as_os_str(), self.get(*ino).expect("inconsistent fs - found child without inode")))
     }
 
     // All inodes have a parent (root parent is root)
@@ -160,10 +399,45 @@ impl InodeStore {
     //         .and_then(move |ino| self.get_mut(ino))
     // }
 
-    pub fn insert(&mut self, inode: Inode) {
+    pub fn insert(&mut self, mut inode: Inode) {
         let ino = inode.attr.ino;
         let path = inode.path.clone();
         let sequence = path_to_sequence(&inode.path);
+        self.remove_negative(&path);
+        let known = self.known.remove(&path);
+        if let Some((known_ino, _)) = known {
+            self.reserved.remove(&known_ino);
+        }
+
+        let is_dir = inode.attr.kind == FileType::Directory;
+        let was_dir = self.inode_map.get(&ino).map(|old_inode| old_inode.attr.kind == FileType::Directory);
+
+        // Keep the directory cookie and listing state of an inode being updated, so listings stay stable
+        match self.inode_map.get(&ino) {
+            Some(old_inode) => {
+                inode.cookie = old_inode.cookie;
+                inode.visited = inode.visited || old_inode.visited;
+                inode.aliases = old_inode.aliases.clone();
+                inode.lookups = old_inode.lookups;
+                inode.generation = old_inode.generation;
+                inode.users.extend(old_inode.users.iter().cloned());
+                inode.attr.nlink = old_inode.attr.nlink;
+            }
+            None => {
+                self.last_cookie += 1;
+                inode.cookie = self.last_cookie;
+                // A path that gets its persisted inode number back is the same inode as before
+                inode.generation = match known {
+                    Some((known_ino, generation)) if known_ino == ino => generation,
+                    _ => self.generation,
+                };
+                self.state_dirty = true;
+            }
+        }
+
+        if !is_dir {
+            inode.attr.nlink = 1 + inode.aliases.len() as u32;
+        }
 
         if let Some(old_inode) = self.inode_map.insert(ino, inode) {
             if old_inode.path != path {
@@ -175,6 +449,11 @@ impl InodeStore {
 
         }
 
+        if let Some(id) = self.inode_map[&ino].id {
+            self.ids.insert(id, ino);
+        }
+
+        self.unindex(&sequence);
         if !self.ino_trie.insert(&sequence, ino) {
             let mut node = self.ino_trie.get_mut_node(&sequence)
                                 .expect(&format!("Corrupt inode store: couldn't insert or modify ino_trie at {:?}", &sequence));
@@ -184,6 +463,145 @@ impl InodeStore {
             // }
             node.value = Some(ino);
         }
+        self.index(&sequence, ino);
+
+        match (was_dir, is_dir) {
+            (Some(true), true) | (Some(false), false) | (None, false) => (),
+            (Some(true), false) => self.add_parent_links(ino, -1),
+            (_, true) => {
+                self[ino].attr.nlink = self.directory_links(ino);
+                self.add_parent_links(ino, 1);
+            }
+        }
+    }
+
+    // Marks whether all children of the directory `ino` are cached, which makes its link count known
+    pub fn set_visited(&mut self, ino: u64, visited: bool) {
+        self[ino].visited = visited;
+        if self[ino].attr.kind == FileType::Directory {
+            self[ino].attr.nlink = self.directory_links(ino);
+        }
+    }
+
+    // A directory has a link from its parent, one from its own ".", and one from each subdirectory's "..".
+    // Until it has been listed its subdirectories aren't all known, so it has 1 link, which tools
+    // like find take to mean that the count is unknown rather than that it has no subdirectories.
+    fn directory_links(&self, ino: u64) -> u32 {
+        match self[ino].visited {
+            true => 2 + self.subdirectory_count(ino),
+            false => 1,
+        }
+    }
+
+    fn subdirectory_count(&self, ino: u64) -> u32 {
+        self.children_after(ino, 0).filter(|(_, child)| child.attr.kind == FileType::Directory).count() as u32
+    }
+
+    fn has_children(&self, ino: u64) -> bool {
+        self.children_after(ino, 0).next().is_some()
+    }
+
+    // Adjusts the link count of the parent of directory `ino` as it is added or removed,
+    // if the parent has been listed
+    fn add_parent_links(&mut self, ino: u64, delta: i32) {
+        if ino == 1 {
+            return;
+        }
+        let parent = match self.parent(ino) {
+            Some(parent) if parent.visited => parent.attr.ino,
+            _ => return,
+        };
+        let nlink = &mut self[parent].attr.nlink;
+        *nlink = ((*nlink as i64) + (delta as i64)).max(2) as u32;
+    }
+
+    pub fn len(&self) -> usize {
+        self.inode_map.len()
+    }
+
+    // Counts a reference the kernel took on `ino` by receiving it in an entry reply
+    pub fn add_lookup(&mut self, ino: u64) -> &Inode {
+        let inode = &mut self[ino];
+        inode.lookups += 1;
+        inode
+    }
+
+    // Releases `nlookup` kernel references on `ino`, returning how many remain
+    pub fn forget(&mut self, ino: u64, nlookup: u64) -> u64 {
+        match self.get_mut(ino) {
+            Some(inode) => {
+                inode.lookups = inode.lookups.saturating_sub(nlookup);
+                inode.lookups
+            }
+            None => 0,
+        }
+    }
+
+    // Inodes that could be evicted: not root, not referenced by the kernel, and without cached children
+    pub fn unreferenced(&self) -> Vec<u64> {
+        self.inode_map
+            .values()
+            .filter(|inode| inode.attr.ino != 1 && inode.lookups == 0)
+            .filter(|inode| inode.attr.kind != FileType::Directory || !self.has_children(inode.attr.ino))
+            .map(|inode| inode.attr.ino)
+            .collect()
+    }
+
+    // Removes an unreferenced inode from the store to bound its memory. Its parent no longer
+    // counts as fully listed, so its link count isn't known anymore either, and the inode
+    // number is kept in case it is persisted.
+    // Returns false if the inode is still referenced or has cached children.
+    pub fn evict(&mut self, ino: u64) -> bool {
+        let (path, generation) = match self.get(ino) {
+            Some(inode) if ino != 1 && inode.lookups == 0 && !self.has_children(ino) => {
+                (inode.path.clone(), inode.generation)
+            }
+            _ => return false,
+        };
+
+        debug!("evicting ino {} {}", ino, path.display());
+        if let Some(parent) = self.parent(ino).map(|parent| parent.attr.ino) {
+            self.set_visited(parent, false);
+        }
+        self.remove(ino);
+        if self.state_file.is_some() {
+            self.reserve(path, ino, generation);
+        }
+        true
+    }
+
+    // Remember that nothing exists at `path` until `expires`
+    pub fn insert_negative<P: AsRef<Path>>(&mut self, path: P, expires: Instant) {
+        if self.negative.len() >= NEGATIVE_PURGE_THRESHOLD {
+            let now = Instant::now();
+            self.negative.retain(|_, expires| *expires > now);
+        }
+        self.negative.insert(path.as_ref().to_owned(), expires);
+    }
+
+    // True if `path` was recently found not to exist
+    pub fn is_negative<P: AsRef<Path>>(&mut self, path: P) -> bool {
+        let expired = match self.negative.get(path.as_ref()) {
+            Some(expires) => *expires <= Instant::now(),
+            None => return false,
+        };
+        if expired {
+            self.negative.remove(path.as_ref());
+        }
+        !expired
+    }
+
+    pub fn remove_negative<P: AsRef<Path>>(&mut self, path: P) {
+        self.negative.remove(path.as_ref());
+    }
+
+    // Removes an inode along with any inodes cached beneath it
+    pub fn remove_tree(&mut self, ino: u64) {
+        let children: Vec<u64> = self.children(ino).iter().map(|child| child.attr.ino).collect();
+        for child in children {
+            self.remove_tree(child);
+        }
+        self.remove(ino);
     }
 
     pub fn remove(&mut self, ino: u64) {
@@ -192,12 +610,187 @@ impl InodeStore {
             path_to_sequence(&path)
         };
 
-        self.inode_map.remove(&ino);
-        self.ino_trie.remove(&sequence);
+        if self.inode_map[&ino].attr.kind == FileType::Directory {
+            self.add_parent_links(ino, -1);
+        }
+
+        self.trie_remove(&sequence);
+        for alias in self.inode_map[&ino].aliases.clone() {
+            let alias_sequence = path_to_sequence(&alias);
+            if self.ino_trie.get(&alias_sequence) == Some(&ino) {
+                self.trie_remove(&alias_sequence);
+            }
+        }
+        let inode = self.inode_map.remove(&ino).unwrap();
+        if let Some(id) = inode.id {
+            self.ids.remove(&id);
+        }
+        // Whichever inode gets this number next must not be mistaken for this one
+        self.generation += 1;
+        self.state_dirty = true;
 
         assert!(self.inode_map.get(&ino).is_none());
         assert!(self.ino_trie.get(&sequence).is_none());
     }
+
+    // Maps `sequence` to `ino` in the trie and the listing of its directory
+    fn trie_insert(&mut self, sequence: &[OsString], ino: u64) {
+        self.unindex(sequence);
+        self.ino_trie.insert(sequence, ino);
+        self.index(sequence, ino);
+    }
+
+    fn trie_remove(&mut self, sequence: &[OsString]) {
+        self.unindex(sequence);
+        self.ino_trie.remove(sequence);
+    }
+
+    // Adds `ino` to the listing of the directory containing `sequence`
+    fn index(&mut self, sequence: &[OsString], ino: u64) {
+        if let Some((name, dir)) = sequence.split_last().filter(|&(_, dir)| !dir.is_empty()) {
+            let cookie = self.inode_map[&ino].cookie;
+            self.listings.entry(dir.to_vec()).or_default().insert((cookie, name.clone()), ino);
+        }
+    }
+
+    // Removes whatever `sequence` maps to in the trie from the listing of its directory
+    fn unindex(&mut self, sequence: &[OsString]) {
+        let ino = match self.ino_trie.get(sequence) {
+            Some(ino) => *ino,
+            None => return,
+        };
+        let (name, dir) = match sequence.split_last() {
+            Some((name, dir)) if !dir.is_empty() => (name, dir),
+            _ => return,
+        };
+        let cookie = self.inode_map.get(&ino).map(|inode| inode.cookie);
+        if let Some(listing) = self.listings.get_mut(dir) {
+            match cookie {
+                Some(cookie) => { listing.remove(&(cookie, name.clone())); }
+                // the inode is gone already, so its cookie isn't known
+                None => listing.retain(|key, _| &key.1 != name),
+            }
+            if listing.is_empty() {
+                self.listings.remove(dir);
+            }
+        }
+    }
+}
+
+// Persisting inode numbers
+//
+// The state file maps paths to inode numbers so that they stay the same across mounts.
+// Metadata is not persisted: a loaded mapping is only used once the path is looked up or listed again.
+// Each line after the header is either `last_ino <n>`, `generation <n>` or `<ino> <generation> <path>`,
+// with bytes of the path that are not printable ASCII, and `%`, escaped as `%XX`.
+// Version 1 files have no generations, and their lines are `<ino> <path>`.
+impl InodeStore {
+    // Loads the mapping from `state_file`, if it exists, and persists changes back to it
+    pub fn load_state<P: AsRef<Path>>(&mut self, state_file: P) -> io::Result<()> {
+        let state_file = state_file.as_ref();
+        self.state_file = Some(state_file.to_owned());
+
+        let file = match File::open(state_file) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        let has_generations = match lines.next() {
+            Some(Ok(ref header)) if header == STATE_HEADER => true,
+            Some(Ok(ref header)) if header == STATE_HEADER_V1 => false,
+            Some(Err(err)) => return Err(err),
+            _ => return Err(invalid_state("missing header")),
+        };
+
+        let mut generation = 0;
+        for line in lines {
+            let line = line?;
+            let mut fields = line.splitn(2, ' ');
+            let (key, value) = match (fields.next(), fields.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(invalid_state(&line)),
+            };
+            match key {
+                "last_ino" => {
+                    let last_ino = value.parse().map_err(|_| invalid_state(&line))?;
+                    self.last_ino = self.last_ino.max(last_ino);
+                    continue;
+                }
+                "generation" => {
+                    generation = value.parse().map_err(|_| invalid_state(&line))?;
+                    continue;
+                }
+                _ => (),
+            }
+
+            let ino: u64 = key.parse().map_err(|_| invalid_state(&line))?;
+            let (ino_generation, value) = match has_generations {
+                true => {
+                    let mut fields = value.splitn(2, ' ');
+                    match (fields.next().and_then(|g| g.parse().ok()), fields.next()) {
+                        (Some(ino_generation), Some(value)) => (ino_generation, value),
+                        _ => return Err(invalid_state(&line)),
+                    }
+                }
+                false => (0, value),
+            };
+            let path = decode_path(value).ok_or_else(|| invalid_state(&line))?;
+            if ino > 1 && self.get_by_path(&path).is_none() {
+                self.last_ino = self.last_ino.max(ino);
+                generation = generation.max(ino_generation);
+                self.reserve(path, ino, ino_generation);
+            }
+        }
+
+        // Numbers may have been released after the file was last saved,
+        // so inodes of this mount start at a generation the previous mount never reached
+        self.generation = self.generation.max(generation + 1);
+
+        debug!("loaded {} inode numbers from {}", self.known.len(), state_file.display());
+        Ok(())
+    }
+
+    // Writes the mapping to the state file if it changed, and if `force` is set
+    // or it was last written longer than `interval` ago
+    pub fn save_state(&mut self, force: bool, interval: Duration) -> io::Result<()> {
+        let state_file = match self.state_file {
+            Some(ref state_file) if self.state_dirty => state_file.clone(),
+            _ => return Ok(()),
+        };
+        if !force && self.state_saved.elapsed() < interval {
+            return Ok(());
+        }
+
+        // Write to a temporary file and rename it over the state file, so a crash leaves either version
+        let mut tmp_name = state_file.file_name().map(|name| name.to_owned()).unwrap_or_default();
+        tmp_name.push(".tmp");
+        let tmp_file = state_file.with_file_name(tmp_name);
+        {
+            let file = File::create(&tmp_file)?;
+            let mut writer = BufWriter::new(&file);
+            writeln!(writer, "{}", STATE_HEADER)?;
+            writeln!(writer, "last_ino {}", self.last_ino)?;
+            writeln!(writer, "generation {}", self.generation)?;
+            for (ino, inode) in &self.inode_map {
+                if *ino != 1 {
+                    writeln!(writer, "{} {} {}", ino, inode.generation, encode_path(&inode.path))?;
+                }
+            }
+            for (path, &(ino, generation)) in &self.known {
+                writeln!(writer, "{} {} {}", ino, generation, encode_path(path))?;
+            }
+            writer.flush()?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_file, &state_file)?;
+
+        debug!("saved inode numbers to {}", state_file.display());
+        self.state_dirty = false;
+        self.state_saved = Instant::now();
+        Ok(())
+    }
 }
 
 impl Index<u64> for InodeStore {
@@ -214,15 +807,55 @@ impl IndexMut<u64> for InodeStore {
     }
 }
 
+// Number of 512-byte blocks needed to hold `size` bytes
+fn blocks_for_size(size: u64) -> u64 {
+    size.div_ceil(512)
+}
+
 fn path_to_sequence(path: &Path) -> Vec<OsString> {
     path.iter().map(|s| s.to_owned() ).collect()
 }
 
+fn invalid_state(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid inode state: {}", line))
+}
+
+pub fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'%' => encoded.push_str("%25"),
+            0x20..=0x7e => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+pub fn decode_path(encoded: &str) -> Option<PathBuf> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut iter = encoded.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                let hex = ::std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use time;
+    use std::env;
     use std::path::Path;
+    use std::process;
+    use std::time::{Duration, Instant};
     use fuse::{FileType, FileAttr};
 
     fn new_dir_attr(ino: u64) -> FileAttr {
@@ -265,6 +898,28 @@ mod tests {
         }
     }
 
+    fn new_metadata() -> Metadata {
+        let now = time::now_utc().to_timespec();
+        Metadata {
+            size: 42,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::RegularFile,
+            perm: 0o640,
+            version: None,
+            id: None,
+            blocks: None,
+            owner: None,
+            group: None,
+        }
+    }
+
+    fn new_metadata_with_id(id: u64) -> Metadata {
+        Metadata { id: Some(id), ..new_metadata() }
+    }
+
     fn build_basic_store() -> InodeStore {
         let mut store = InodeStore::new(0o750, 1000, 1000);
         store.insert(Inode::new("/data", new_dir_attr(2)));
@@ -330,6 +985,30 @@ mod tests {
         assert_eq!(store.children(3).len(), 0);
     }
 
+    #[test]
+    fn test_inode_store_children_after() {
+        let mut store = build_basic_store();
+        let cookies: Vec<u64> = store.children(2).iter().map(|c| c.cookie).collect();
+        assert!(cookies[0] > 2);
+        assert!(cookies[0] < cookies[1]);
+
+        let after_first: Vec<_> = store.children_after(2, cookies[0]).collect();
+        assert_eq!(after_first.len(), 1);
+        assert_eq!(after_first[0].1.path, Path::new("/data/bar.txt"));
+
+        // updating an inode keeps its cookie and new inodes are listed last
+        store.insert(Inode::new("/data/foo.txt", new_file_attr(3)));
+        store.insert(Inode::new("/data/baz.txt", new_file_attr(5)));
+        let paths: Vec<&Path> = store.children_after(2, 0).map(|c| c.1.path.as_path()).collect();
+        assert_eq!(paths, vec![Path::new("/data/foo.txt"), Path::new("/data/bar.txt"), Path::new("/data/baz.txt")]);
+        assert!(store.children_after(2, store.get(5).unwrap().cookie).next().is_none());
+
+        // listing goes by the directory index, which follows removals
+        store.remove(3);
+        let paths: Vec<&Path> = store.children_after(2, 0).map(|c| c.1.path.as_path()).collect();
+        assert_eq!(paths, vec![Path::new("/data/bar.txt"), Path::new("/data/baz.txt")]);
+    }
+
     #[test]
     fn test_inode_store_child() {
         let store = build_basic_store();
@@ -337,6 +1016,261 @@ mod tests {
         assert!(store.child(2, Path::new("notfound")).is_none());
     }
 
+    #[test]
+    fn test_inode_store_negative() {
+        let mut store = build_basic_store();
+        let later = Instant::now() + Duration::from_secs(60);
+        store.insert_negative("/data/.git", later);
+        store.insert_negative("/data/expired", Instant::now());
+        assert!(store.is_negative("/data/.git"));
+        assert!(!store.is_negative("/data/expired"));
+        assert!(!store.is_negative("/data/foo.txt"));
+
+        // creating the path clears the negative entry
+        store.insert(Inode::new("/data/.git", new_dir_attr(5)));
+        assert!(!store.is_negative("/data/.git"));
+    }
+
+    #[test]
+    fn test_inode_store_remove_tree() {
+        let mut store = build_basic_store();
+        store.get_mut(2).unwrap().visited = true;
+        store.remove_tree(2);
+        assert!(store.get(2).is_none());
+        assert!(store.get(3).is_none());
+        assert!(store.get_by_path("/data/bar.txt").is_none());
+        assert_eq!(store.children(1).len(), 0);
+    }
+
+    #[test]
+    fn test_inode_store_owners() {
+        let mut store = InodeStore::new(0o750, 1000, 1000);
+        let owned = Metadata { owner: Some("bob".into()), group: Some("staff".into()), ..new_metadata() };
+        assert_eq!(store.insert_metadata("/a.txt", &owned).attr.uid, 1000);
+
+        store.set_id_map(IdMap::new().user("bob", 1001).group("staff", 50));
+        let attr = store.insert_metadata("/a.txt", &owned).attr;
+        assert_eq!((attr.uid, attr.gid), (1001, 50));
+        let unmapped = Metadata { owner: Some("mallory".into()), ..new_metadata() };
+        assert_eq!(store.insert_metadata("/b.txt", &unmapped).attr.uid, 1000);
+    }
+
+    #[test]
+    fn test_inode_store_nlink_and_blocks() {
+        let mut store = InodeStore::new(0o750, 1000, 1000);
+        let dir = Metadata { kind: FileType::Directory, size: 0, ..new_metadata() };
+        let data = store.insert_metadata("/data", &dir).attr.ino;
+        store.insert_metadata("/data/sub", &dir);
+        let file = store.insert_metadata("/data/foo.txt", &new_metadata()).attr;
+        assert_eq!((file.nlink, file.blocks), (1, 1));
+        assert_eq!(store.insert_metadata("/big.bin", &Metadata { size: 1025, ..new_metadata() }).attr.blocks, 3);
+        assert_eq!(store.insert_metadata("/sparse.bin", &Metadata { blocks: Some(0), ..new_metadata() }).attr.blocks, 0);
+
+        // directories that haven't been listed don't know their link count
+        assert_eq!(store.get(1).unwrap().attr.nlink, 1);
+        assert_eq!(store.get(data).unwrap().attr.nlink, 1);
+        store.set_visited(1, true);
+        store.set_visited(data, true);
+        assert_eq!(store.get(1).unwrap().attr.nlink, 3);
+        assert_eq!(store.get(data).unwrap().attr.nlink, 3);
+
+        // updating a directory keeps its link count, and removing a subdirectory drops it
+        assert_eq!(store.insert_metadata("/data", &dir).attr.nlink, 3);
+        let sub = store.get_by_path("/data/sub").unwrap().attr.ino;
+        store.remove(sub);
+        assert_eq!(store.get(data).unwrap().attr.nlink, 2);
+
+        // children cached before their directory are counted once it is listed
+        store.insert_metadata("/other/sub", &dir);
+        let other = store.insert_metadata("/other", &dir).attr.ino;
+        assert_eq!(store.get(other).unwrap().attr.nlink, 1);
+        store.set_visited(other, true);
+        assert_eq!(store.get(other).unwrap().attr.nlink, 3);
+        assert_eq!(store.get(1).unwrap().attr.nlink, 4);
+
+        // evicting a subdirectory makes the count unknown again rather than dropping it
+        let sub = store.get_by_path("/other/sub").unwrap().attr.ino;
+        assert!(store.evict(sub));
+        assert_eq!(store.get(other).unwrap().attr.nlink, 1);
+    }
+
+    #[test]
+    fn test_encode_path() {
+        let path = Path::new(OsStr::from_bytes(b"/data/100% caf\xc3\xa9\nfoo"));
+        let encoded = encode_path(path);
+        assert_eq!(encoded, "/data/100%25 caf%C3%A9%0Afoo");
+        assert_eq!(decode_path(&encoded).unwrap(), path);
+        assert!(decode_path("/data/%4").is_none());
+    }
+
+    #[test]
+    fn test_inode_store_state() {
+        let state_file = env::temp_dir().join(format!("netfuse-test-{}.state", process::id()));
+        let _ = fs::remove_file(&state_file);
+
+        let mut store = build_basic_store();
+        store.load_state(&state_file).unwrap();
+        store.save_state(false, Duration::from_secs(60)).unwrap();
+        assert!(!state_file.exists());
+        store.save_state(true, Duration::from_secs(60)).unwrap();
+
+        // A new store reuses the saved inode numbers and numbers new paths after them
+        let mut store = InodeStore::new(0o750, 1000, 1000);
+        store.load_state(&state_file).unwrap();
+        fs::remove_file(&state_file).unwrap();
+        assert_eq!(store.insert_metadata("/data/bar.txt", &new_metadata()).attr.ino, 4);
+        assert_eq!(store.insert_metadata("/data/baz.txt", &new_metadata()).attr.ino, 5);
+        assert_eq!(store.insert_metadata("/data", &new_metadata()).attr.ino, 2);
+    }
+
+    #[test]
+    fn test_inode_store_state_reserves_numbers() {
+        let state_file = env::temp_dir().join(format!("netfuse-test-{}-reserved.state", process::id()));
+        let _ = fs::remove_file(&state_file);
+
+        let mut store = InodeStore::new(0o750, 1000, 1000);
+        store.load_state(&state_file).unwrap();
+        assert_eq!(store.insert_metadata("/a.txt", &new_metadata()).attr.ino, 2);
+        store.save_state(true, Duration::from_secs(60)).unwrap();
+
+        // New paths of the next mount don't take numbers persisted for paths not looked up yet
+        let mut store = InodeStore::new(0o750, 1000, 1000);
+        store.load_state(&state_file).unwrap();
+        fs::remove_file(&state_file).unwrap();
+        assert_eq!(store.insert_metadata("/b.txt", &new_metadata_with_id(2)).attr.ino, 3);
+        assert_eq!(store.insert_metadata("/a.txt", &new_metadata()).attr.ino, 2);
+        assert_eq!(store.insert_metadata("/c.txt", &new_metadata()).attr.ino, 4);
+    }
+
+    #[test]
+    fn test_inode_store_generations() {
+        let state_file = env::temp_dir().join(format!("netfuse-test-{}-gen.state", process::id()));
+        let _ = fs::remove_file(&state_file);
+
+        let mut store = InodeStore::new(0o750, 1000, 1000);
+        store.load_state(&state_file).unwrap();
+        let first = store.insert_metadata("/a.txt", &new_metadata_with_id(100)).generation;
+        assert_eq!(store.insert_metadata("/a.txt", &new_metadata_with_id(100)).generation, first);
+
+        // a reused inode number gets a new generation
+        store.remove(100);
+        let second = store.insert_metadata("/b.txt", &new_metadata_with_id(100)).generation;
+        assert!(second > first);
+
+        // an evicted inode keeps its generation if its path gets the same number back
+        assert!(store.evict(100));
+        assert_eq!(store.insert_metadata("/b.txt", &new_metadata()).generation, second);
+        store.save_state(true, Duration::from_secs(60)).unwrap();
+
+        // generations are persisted, and new inodes of the next mount don't reuse old ones
+        let mut store = InodeStore::new(0o750, 1000, 1000);
+        store.load_state(&state_file).unwrap();
+        fs::remove_file(&state_file).unwrap();
+        assert_eq!(store.insert_metadata("/b.txt", &new_metadata()).generation, second);
+        assert!(store.insert_metadata("/c.txt", &new_metadata()).generation > second);
+    }
+
+    #[test]
+    fn test_inode_store_ids() {
+        let mut store = build_basic_store();
+        assert_eq!(store.insert_metadata("/data/a.txt", &new_metadata_with_id(100)).attr.ino, 100);
+        // reserved and taken numbers fall back to sequential numbers
+        assert_eq!(store.insert_metadata("/data/b.txt", &new_metadata_with_id(1)).attr.ino, 5);
+        assert_eq!(store.insert_metadata("/data/c.txt", &new_metadata_with_id(3)).attr.ino, 6);
+
+        // the same object at another path keeps its inode, and both names resolve to it
+        assert_eq!(store.insert_metadata("/data/renamed.txt", &new_metadata_with_id(100)).attr.ino, 100);
+        assert_eq!(store.get(100).unwrap().path, Path::new("/data/renamed.txt"));
+        assert_eq!(store.child(2, "a.txt").unwrap().attr.ino, 100);
+        let names: Vec<&OsStr> = store.named_children(2).iter().map(|&(name, _)| name).collect();
+        assert!(names.contains(&OsStr::new("a.txt")) && names.contains(&OsStr::new("renamed.txt")));
+
+        // a different object replacing a path gets a new inode
+        assert_eq!(store.insert_metadata("/data/b.txt", &new_metadata_with_id(200)).attr.ino, 200);
+        assert!(store.get(5).is_none());
+
+        store.remove(100);
+        assert!(store.get_by_path("/data/a.txt").is_none());
+        assert!(store.get_by_path("/data/renamed.txt").is_none());
+        assert_eq!(store.insert_metadata("/data/a.txt", &new_metadata_with_id(100)).attr.ino, 100);
+    }
+
+    #[test]
+    fn test_inode_store_move_directory() {
+        let mut store = InodeStore::new(0o750, 1000, 1000);
+        let dir = Metadata { kind: FileType::Directory, ..new_metadata_with_id(100) };
+        store.insert_metadata("/d", &dir);
+        store.insert_metadata("/d/f", &new_metadata_with_id(101));
+        store.insert_metadata("/d/sub", &Metadata { kind: FileType::Directory, ..new_metadata_with_id(102) });
+        store.insert_metadata("/d/sub/g", &new_metadata_with_id(103));
+        store.get_mut(100).unwrap().visited = true;
+
+        // the directory shows up at its new path, and its children move along
+        assert_eq!(store.insert_metadata("/e", &dir).attr.ino, 100);
+        assert_eq!(store.child(100, "f").unwrap().attr.ino, 101);
+        assert_eq!(store.children(100).len(), 2);
+        assert_eq!(store.get(103).unwrap().path, Path::new("/e/sub/g"));
+        assert_eq!(store.get_by_path("/e/sub/g").unwrap().attr.ino, 103);
+        assert!(store.get_by_path("/d/f").is_none());
+        assert_eq!(store.parent(101).unwrap().attr.ino, 100);
+    }
+
+    #[test]
+    fn test_inode_store_replace_directory() {
+        let mut store = InodeStore::new(0o750, 1000, 1000);
+        store.insert_metadata("/d", &Metadata { kind: FileType::Directory, ..new_metadata_with_id(100) });
+        store.insert_metadata("/d/f", &new_metadata_with_id(101));
+
+        // the children of the replaced directory don't show up in the new one
+        let ino = store.insert_metadata("/d", &Metadata { kind: FileType::Directory, ..new_metadata_with_id(200) }).attr.ino;
+        assert_eq!(ino, 200);
+        assert!(store.children(200).is_empty());
+        assert!(store.get(101).is_none());
+        assert!(store.get_by_path("/d/f").is_none());
+    }
+
+    #[test]
+    fn test_inode_store_drop_unlisted_aliases() {
+        let mut store = InodeStore::new(0o750, 1000, 1000);
+        store.insert_metadata("/a.txt", &new_metadata_with_id(100));
+        store.insert_metadata("/b.txt", &new_metadata_with_id(100));
+        assert_eq!(store.get(100).unwrap().attr.nlink, 2);
+
+        // a listing with both names keeps them
+        let mut listed: HashSet<OsString> = vec![OsString::from("a.txt"), OsString::from("b.txt")].into_iter().collect();
+        store.drop_unlisted_aliases(1, &listed);
+        assert_eq!(store.get_by_path("/a.txt").unwrap().attr.ino, 100);
+
+        // once the old name is gone from a listing, it no longer resolves
+        listed.remove(OsStr::new("a.txt"));
+        store.drop_unlisted_aliases(1, &listed);
+        assert!(store.get_by_path("/a.txt").is_none());
+        assert_eq!(store.get_by_path("/b.txt").unwrap().attr.ino, 100);
+        assert_eq!(store.get(100).unwrap().attr.nlink, 1);
+        assert_eq!(store.children(1).len(), 1);
+    }
+
+    #[test]
+    fn test_inode_store_evict() {
+        let mut store = build_basic_store();
+        store.get_mut(2).unwrap().visited = true;
+        store.add_lookup(3);
+        store.add_lookup(3);
+        assert_eq!(store.forget(3, 1), 1);
+        assert_eq!(store.unreferenced(), vec![4]);
+        assert!(!store.evict(3));
+        assert!(!store.evict(2));
+
+        assert_eq!(store.forget(3, 1), 0);
+        assert!(store.evict(3));
+        assert!(store.evict(4));
+        assert!(store.get_by_path("/data/foo.txt").is_none());
+        assert!(!store.get(2).unwrap().visited);
+        assert_eq!(store.unreferenced(), vec![2]);
+        assert!(!store.evict(1));
+        assert_eq!(store.len(), 2);
+    }
+
     #[test]
     fn test_inode_store_insert_backward() {
         let mut store = InodeStore::new(0o750, 1000, 1000);
@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use libc::EIO;
+use inode::{decode_path, encode_path};
+use super::{Caller, LibcError, NetworkFilesystem, is_unreachable};
+
+// Header line identifying a journal log
+const JOURNAL_HEADER: &str = "netfuse-journal 1";
+// Name of the log within the journal directory
+const LOG_NAME: &str = "journal";
+
+// Change to the backend recorded in the journal
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum JournalOp {
+    // writes the data saved with the entry, creating the file if needed
+    Write,
+    Mkdir,
+    Rmdir,
+    Unlink,
+}
+
+impl JournalOp {
+    fn name(&self) -> &'static str {
+        match *self {
+            JournalOp::Write => "write",
+            JournalOp::Mkdir => "mkdir",
+            JournalOp::Rmdir => "rmdir",
+            JournalOp::Unlink => "unlink",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<JournalOp> {
+        match name {
+            "write" => Some(JournalOp::Write),
+            "mkdir" => Some(JournalOp::Mkdir),
+            "rmdir" => Some(JournalOp::Rmdir),
+            "unlink" => Some(JournalOp::Unlink),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub op: JournalOp,
+    pub path: PathBuf,
+    // uid and gid of the caller the change is replayed as
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// Replayed entry, with the new version of written files
+pub type JournalResult = (JournalEntry, Result<Option<String>, LibcError>);
+
+// Log of changes that were acknowledged before reaching the backend, replayed in order by a worker thread
+//
+// The log is a header followed by lines `<seq> <op> <uid> <gid> <path>` for recorded entries
+// and `done <seq>` for replayed ones, with paths escaped like in the inode state file.
+// The data of each write is saved next to the log as `<seq>.data`. Entries are synced to disk
+// before they are acknowledged, and entries not replayed yet are queued again when mounting.
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    log: Arc<Mutex<File>>,
+    last_seq: u64,
+    // paths with entries not replayed yet, with the number of entries, the seq of the data
+    // the file has once they are replayed, if its last pending entry is a write, and the last pending op
+    pending: HashMap<PathBuf, (usize, Option<u64>, JournalOp)>,
+    jobs: Sender<JournalEntry>,
+    results: Receiver<JournalResult>,
+    // set when closing, so the worker stops waiting for the backend
+    closed: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Journal {
+    // Opens the journal in `dir`, creating it if needed, and starts replaying the entries left
+    // from earlier mounts. Entries that fail because the backend is unreachable are retried every
+    // `retry_interval`. Returns None if the backend provides no handle for replaying.
+    pub fn open<NFS: NetworkFilesystem>(dir: &Path, nfs: &NFS, retry_interval: Duration) -> io::Result<Option<Journal>> {
+        let replayer = match nfs.clone_for_journal() {
+            Some(replayer) => replayer,
+            None => return Ok(None),
+        };
+
+        fs::create_dir_all(dir)?;
+        let entries = load_pending(dir)?;
+        let last_seq = entries.last().map(|entry| entry.seq).unwrap_or(0);
+        let log = Arc::new(Mutex::new(rewrite_log(dir, &entries)?));
+
+        let (jobs, job_queue) = mpsc::channel();
+        let (result_sender, results) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let dir = dir.to_owned();
+            let log = log.clone();
+            let closed = closed.clone();
+            thread::spawn(move || replay(replayer, &dir, &log, job_queue, result_sender, &closed, retry_interval))
+        };
+
+        let mut journal = Journal {
+            dir: dir.to_owned(),
+            log: log,
+            last_seq: last_seq,
+            pending: HashMap::new(),
+            jobs: jobs,
+            results: results,
+            closed: closed,
+            worker: Some(worker),
+        };
+        if !entries.is_empty() {
+            info!("replaying {} journaled changes from {}", entries.len(), dir.display());
+        }
+        for entry in entries {
+            journal.queue(entry);
+        }
+        Ok(Some(journal))
+    }
+
+    // Records a write of `data` to `path`, which replaces whatever is pending for the file
+    pub fn write(&mut self, caller: &Caller, path: &Path, data: &[u8]) -> io::Result<()> {
+        let seq = self.last_seq + 1;
+        let data_file = self.dir.join(format!("{}.data", seq));
+        {
+            let mut file = File::create(&data_file)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+        }
+        self.record(seq, JournalOp::Write, caller, path).inspect_err(|_| {
+            let _ = fs::remove_file(&data_file);
+        })
+    }
+
+    // Records a change that carries no data
+    pub fn change(&mut self, op: JournalOp, caller: &Caller, path: &Path) -> io::Result<()> {
+        let seq = self.last_seq + 1;
+        self.record(seq, op, caller, path)
+    }
+
+    fn record(&mut self, seq: u64, op: JournalOp, caller: &Caller, path: &Path) -> io::Result<()> {
+        let entry = JournalEntry { seq: seq, op: op, path: path.to_owned(), uid: caller.uid, gid: caller.gid };
+        {
+            let log = self.log.lock().unwrap();
+            write_entry(&*log, &entry)?;
+            log.sync_data()?;
+        }
+        self.last_seq = seq;
+        debug!("journaled {} {}", op.name(), path.display());
+        self.queue(entry);
+        Ok(())
+    }
+
+    fn queue(&mut self, entry: JournalEntry) {
+        let pending = self.pending.entry(entry.path.clone()).or_insert((0, None, entry.op));
+        pending.0 += 1;
+        pending.1 = match entry.op {
+            JournalOp::Write => Some(entry.seq),
+            _ => None,
+        };
+        pending.2 = entry.op;
+        let _ = self.jobs.send(entry);
+    }
+
+    // Number of entries not replayed yet
+    pub fn len(&self) -> usize {
+        self.pending.values().map(|&(count, _, _)| count).sum()
+    }
+
+    // true if changes to `path` are waiting to be replayed
+    pub fn is_pending(&self, path: &Path) -> bool {
+        self.pending.contains_key(path)
+    }
+
+    // Last change to `path` that is waiting to be replayed
+    pub fn pending_op(&self, path: &Path) -> Option<JournalOp> {
+        self.pending.get(path).map(|&(_, _, op)| op)
+    }
+
+    // Paths in the directory `dir` with changes waiting to be replayed, with the last of their changes
+    pub fn pending_children(&self, dir: &Path) -> Vec<(PathBuf, JournalOp)> {
+        self.pending.iter()
+            .filter(|(path, _)| path.parent() == Some(dir))
+            .map(|(path, &(_, _, op))| (path.clone(), op))
+            .collect()
+    }
+
+    // Data `path` has once its pending entries are replayed, if the last one is a write
+    pub fn pending_data(&self, path: &Path) -> Option<io::Result<Vec<u8>>> {
+        match self.pending.get(path) {
+            Some(&(_, Some(seq), _)) => Some(fs::read(self.dir.join(format!("{}.data", seq)))),
+            _ => None,
+        }
+    }
+
+    // Returns a replayed entry, if any, and drops its saved data.
+    // The data of writes that failed is kept next to the log as `<seq>.failed`.
+    pub fn next_result(&mut self) -> Option<JournalResult> {
+        let (entry, result) = self.results.try_recv().ok()?;
+        if let Some(pending) = self.pending.get_mut(&entry.path) {
+            pending.0 -= 1;
+            if pending.1 == Some(entry.seq) {
+                pending.1 = None;
+            }
+        }
+        if self.pending.get(&entry.path).map(|&(count, _, _)| count == 0).unwrap_or(false) {
+            self.pending.remove(&entry.path);
+        }
+
+        if entry.op == JournalOp::Write {
+            let data_file = self.dir.join(format!("{}.data", entry.seq));
+            let _ = match result {
+                Ok(_) => fs::remove_file(&data_file),
+                Err(_) => fs::rename(&data_file, data_file.with_extension("failed")),
+            };
+        }
+        Some((entry, result))
+    }
+}
+
+// Entries left to replay stay in the log for the next mount. Waits for the entry being replayed, if any.
+impl Drop for Journal {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let (jobs, _) = mpsc::channel();
+        drop(::std::mem::replace(&mut self.jobs, jobs));
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Applies entries in order with the replaying handle, retrying each until the backend is reachable.
+// Entries are marked replayed once applied or failed for another reason.
+fn replay(mut nfs: Box<dyn NetworkFilesystem + Send>, dir: &Path, log: &Mutex<File>, jobs: Receiver<JournalEntry>,
+          results: Sender<JournalResult>, closed: &AtomicBool, retry_interval: Duration) {
+    for entry in jobs {
+        let caller = Caller::new(entry.uid, entry.gid, 0);
+        let data = match entry.op {
+            JournalOp::Write => fs::read(dir.join(format!("{}.data", entry.seq))),
+            _ => Ok(Vec::new()),
+        };
+
+        let result = match data {
+            Ok(data) => loop {
+                let result = match entry.op {
+                    JournalOp::Write => nfs.write(&caller, &entry.path, &data, None),
+                    JournalOp::Mkdir => nfs.mkdir(&caller, &entry.path).map(|_| None),
+                    JournalOp::Rmdir => nfs.rmdir(&caller, &entry.path).map(|_| None),
+                    JournalOp::Unlink => nfs.unlink(&caller, &entry.path).map(|_| None),
+                };
+                match result {
+                    Err(err) if is_unreachable(err) => {
+                        if closed.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        info!("journal replay of {} {} waiting for the backend - {}", entry.op.name(), entry.path.display(), err);
+                        thread::sleep(retry_interval);
+                    }
+                    result => break result,
+                }
+            },
+            Err(err) => {
+                error!("journaled data of {} is unreadable - {}", entry.path.display(), err);
+                Err(EIO)
+            }
+        };
+        match result {
+            Ok(_) => debug!("journal replayed {} {}", entry.op.name(), entry.path.display()),
+            Err(err) => error!("journal dropped {} {} - {}", entry.op.name(), entry.path.display(), err),
+        }
+
+        if let Err(err) = writeln!(&*log.lock().unwrap(), "done {}", entry.seq) {
+            error!("failed to mark journal entry {} replayed - {}", entry.seq, err);
+        }
+        let _ = results.send((entry, result));
+    }
+}
+
+fn write_entry<W: Write>(mut writer: W, entry: &JournalEntry) -> io::Result<()> {
+    writeln!(writer, "{} {} {} {} {}", entry.seq, entry.op.name(), entry.uid, entry.gid, encode_path(&entry.path))
+}
+
+fn invalid_journal(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid journal: {}", line))
+}
+
+// Reads the entries of the log in `dir` that were not replayed yet
+fn load_pending(dir: &Path) -> io::Result<Vec<JournalEntry>> {
+    let file = match File::open(dir.join(LOG_NAME)) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut lines = BufReader::new(file).lines();
+    match lines.next() {
+        Some(Ok(ref header)) if header == JOURNAL_HEADER => (),
+        Some(Err(err)) => return Err(err),
+        _ => return Err(invalid_journal("missing header")),
+    }
+
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line?;
+        let fields: Vec<&str> = line.splitn(5, ' ').collect();
+        if fields.len() == 2 && fields[0] == "done" {
+            let seq: u64 = fields[1].parse().map_err(|_| invalid_journal(&line))?;
+            entries.retain(|entry: &JournalEntry| entry.seq != seq);
+            continue;
+        }
+        if fields.len() != 5 {
+            // a crash while appending can leave the last line incomplete
+            warn!("ignoring journal line: {}", line);
+            continue;
+        }
+        let entry = JournalEntry {
+            seq: fields[0].parse().map_err(|_| invalid_journal(&line))?,
+            op: JournalOp::from_name(fields[1]).ok_or_else(|| invalid_journal(&line))?,
+            uid: fields[2].parse().map_err(|_| invalid_journal(&line))?,
+            gid: fields[3].parse().map_err(|_| invalid_journal(&line))?,
+            path: decode_path(fields[4]).ok_or_else(|| invalid_journal(&line))?,
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Replaces the log in `dir` with one holding only `entries`, removing saved data of other entries,
+// and returns the new log opened for appending
+fn rewrite_log(dir: &Path, entries: &[JournalEntry]) -> io::Result<File> {
+    let log_file = dir.join(LOG_NAME);
+    let tmp_file = dir.join(format!("{}.tmp", LOG_NAME));
+    {
+        let file = File::create(&tmp_file)?;
+        let mut writer = BufWriter::new(&file);
+        writeln!(writer, "{}", JOURNAL_HEADER)?;
+        for entry in entries {
+            write_entry(&mut writer, entry)?;
+        }
+        writer.flush()?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_file, &log_file)?;
+
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        let seq = match (path.extension(), path.file_stem().and_then(|stem| stem.to_str())) {
+            (Some(ext), Some(stem)) if ext == "data" => stem.parse::<u64>().ok(),
+            _ => None,
+        };
+        if let Some(seq) = seq {
+            if !entries.iter().any(|entry| entry.seq == seq) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+
+    OpenOptions::new().append(true).open(&log_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use libc::{EACCES, ECONNREFUSED};
+
+    // Backend that refuses connections for the first `outages` calls and records the rest
+    struct FlakyFs {
+        outages: Arc<AtomicUsize>,
+        applied: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FlakyFs {
+        fn call(&self, what: String) -> Result<(), LibcError> {
+            if self.outages.load(Ordering::SeqCst) > 0 {
+                self.outages.fetch_sub(1, Ordering::SeqCst);
+                return Err(ECONNREFUSED);
+            }
+            if what.contains(" /forbidden") {
+                return Err(EACCES);
+            }
+            self.applied.lock().unwrap().push(what);
+            Ok(())
+        }
+    }
+
+    impl NetworkFilesystem for FlakyFs {
+        fn clone_for_journal(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+            Some(Box::new(FlakyFs { outages: self.outages.clone(), applied: self.applied.clone() }))
+        }
+
+        fn write(&mut self, _caller: &Caller, path: &Path, data: &[u8], _version: Option<&str>) -> Result<Option<String>, LibcError> {
+            self.call(format!("write {} {}", path.display(), String::from_utf8_lossy(data)))
+                .map(|_| Some(format!("v{}", data.len())))
+        }
+
+        fn mkdir(&mut self, _caller: &Caller, path: &Path) -> Result<(), LibcError> {
+            self.call(format!("mkdir {}", path.display()))
+        }
+
+        fn unlink(&mut self, _caller: &Caller, path: &Path) -> Result<(), LibcError> {
+            self.call(format!("unlink {}", path.display()))
+        }
+    }
+
+    fn next_result(journal: &mut Journal) -> JournalResult {
+        let start = Instant::now();
+        loop {
+            if let Some(result) = journal.next_result() {
+                return result;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "journal replay timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_journal_replays_in_order() {
+        let dir = env::temp_dir().join(format!("netfuse-journal-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let caller = Caller::new(1000, 1000, 1);
+        let backend = FlakyFs { outages: Arc::new(AtomicUsize::new(usize::MAX)), applied: Arc::new(Mutex::new(Vec::new())) };
+
+        // Record entries while the backend is down, then reopen the journal as a new mount would
+        {
+            let mut journal = Journal::open(&dir, &backend, Duration::from_millis(1)).unwrap().unwrap();
+            journal.change(JournalOp::Mkdir, &caller, Path::new("/new dir")).unwrap();
+            journal.write(&caller, Path::new("/new dir/a.txt"), b"first").unwrap();
+            journal.write(&caller, Path::new("/new dir/a.txt"), b"second").unwrap();
+            journal.write(&caller, Path::new("/forbidden"), b"nope").unwrap();
+            journal.change(JournalOp::Unlink, &caller, Path::new("/old.txt")).unwrap();
+            assert_eq!(journal.len(), 5);
+            assert!(journal.is_pending(Path::new("/new dir")));
+            assert_eq!(journal.pending_data(Path::new("/new dir/a.txt")).unwrap().unwrap(), b"second");
+            assert!(journal.pending_data(Path::new("/old.txt")).is_none());
+        }
+        backend.outages.store(3, Ordering::SeqCst);
+        let mut journal = Journal::open(&dir, &backend, Duration::from_millis(1)).unwrap().unwrap();
+        assert_eq!(journal.len(), 5);
+
+        let results: Vec<_> = (0..5).map(|_| {
+            let (entry, result) = next_result(&mut journal);
+            (entry.seq, result)
+        }).collect();
+        assert_eq!(results, vec![(1, Ok(None)), (2, Ok(Some("v5".into()))), (3, Ok(Some("v6".into()))),
+                                 (4, Err(EACCES)), (5, Ok(None))]);
+        assert_eq!(*backend.applied.lock().unwrap(), vec![
+            "mkdir /new dir", "write /new dir/a.txt first", "write /new dir/a.txt second", "unlink /old.txt",
+        ]);
+        assert_eq!(journal.len(), 0);
+        assert!(journal.pending_data(Path::new("/new dir/a.txt")).is_none());
+        assert!(dir.join("4.failed").exists());
+        assert!(!dir.join("3.data").exists());
+
+        // Nothing is replayed again
+        drop(journal);
+        let journal = Journal::open(&dir, &backend, Duration::from_millis(1)).unwrap().unwrap();
+        assert_eq!(journal.len(), 0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -7,6 +7,10 @@
 //! Internally, the call to `netfuse::mount` will create a low-level `NetFuse` filesystem
 //! that handles caching, inode number to path translation, offsets and sizes, and
 //! lazily triggering writes to persist.
+//!
+//! Backends built on futures can instead implement
+//! [`netfuse::AsyncNetworkFilesystem`](trait.AsyncNetworkFilesystem.html)
+//! and call [`netfuse::mount_async`](fn.mount_async.html).
 
 extern crate fuse;
 extern crate libc;
@@ -18,26 +22,85 @@ extern crate log;
 mod inode;
 mod cache;
 mod nfs;
+mod async_nfs;
+mod async_fuse;
+mod prefetch;
+mod idmap;
+mod per_user;
+mod journal;
+mod health;
+mod pin;
 
 pub use nfs::*;
-use inode::InodeStore;
+pub use async_nfs::*;
+pub use prefetch::PrefetchOptions;
+pub use idmap::IdMap;
+pub use health::OfflineOptions;
+pub use pin::{PinStatus, Pins};
+pub use per_user::{CredentialProvider, PerUserFilesystem};
+use inode::{Inode, InodeStore};
 use cache::CacheEntry;
-
-use libc::{EIO, ENOENT, c_int};
-use fuse::{FileType, FileAttr, Filesystem, Request, ReplyEntry, ReplyAttr, ReplyData, ReplyDirectory, ReplyOpen, ReplyEmpty, ReplyWrite};
-use std::collections::HashMap;
-use std::path::Path;
+use prefetch::Prefetcher;
+use journal::{Journal, JournalOp};
+use health::Health;
+use async_fuse::AsyncNetFuse;
+
+use libc::{EACCES, EBADF, EIO, ENODATA, ENOENT, ENOSYS, ENOTEMPTY, ENOTSUP, EPERM, ESTALE, O_ACCMODE, O_APPEND, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, R_OK, W_OK, X_OK, c_int};
+use fuse::consts::FOPEN_DIRECT_IO;
+use fuse::{FileAttr, FileType, Filesystem, Request, ReplyEntry, ReplyAttr, ReplyData, ReplyDirectory, ReplyOpen, ReplyEmpty, ReplyWrite};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
 use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use time::Timespec;
 
 const DEFAULT_TTL: Timespec = Timespec { sec: 1, nsec: 0 };
 
+// Inode and, if users are isolated, the uid that cached data belongs to
+type CacheKey = (u64, Option<u32>);
+
+// Minimum time between writes of the inode state file, except when unmounting
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Minimum time between attempts to write back data whose write-back failed
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+const DEFAULT_UNMOUNT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Time between attempts to replay a journal entry while the backend is unreachable
+const JOURNAL_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+// Extended attribute that pins a path when set and unpins it when removed
+const PIN_XATTR: &str = "user.netfuse.pin";
+
 /// Options for configuring how the `NetworkFilesystem` will be mounted
 #[derive(Debug, Copy, Clone)]
 pub struct MountOptions<'a> {
     path: &'a Path,
     uid: u32,
     gid: u32,
+    readdir_errors: ReaddirErrorPolicy,
+    skipped_entries: Option<&'a SkippedEntries>,
+    negative_ttl: Option<Duration>,
+    lookup_strategy: Option<LookupStrategy>,
+    prefetch: Option<PrefetchOptions>,
+    revalidate_ttl: Option<Duration>,
+    conflict_policy: ConflictPolicy,
+    state_file: Option<&'a Path>,
+    max_inodes: Option<usize>,
+    id_map: Option<&'a IdMap>,
+    default_permissions: bool,
+    caller_cmdline: bool,
+    allow_other: bool,
+    unmount_timeout: Duration,
+    journal: Option<&'a Path>,
+    offline: Option<OfflineOptions>,
+    pins: Option<&'a Pins>,
     // read_only: bool,
 }
 
@@ -47,9 +110,233 @@ impl <'a> MountOptions<'a> {
             path: path.as_ref(),
             uid: unsafe { libc::getuid() } as u32,
             gid: unsafe { libc::getgid() } as u32,
+            readdir_errors: ReaddirErrorPolicy::Abort,
+            skipped_entries: None,
+            negative_ttl: None,
+            lookup_strategy: None,
+            prefetch: None,
+            revalidate_ttl: None,
+            conflict_policy: ConflictPolicy::Fail,
+            state_file: None,
+            max_inodes: None,
+            id_map: None,
+            default_permissions: false,
+            caller_cmdline: false,
+            allow_other: false,
+            unmount_timeout: DEFAULT_UNMOUNT_TIMEOUT,
+            journal: None,
+            pins: None,
+            offline: None,
             // read_only: false,
         }
     }
+
+    /// Sets how errors for individual entries of a directory listing are handled
+    ///
+    /// Defaults to `ReaddirErrorPolicy::Abort`
+    pub fn readdir_errors(mut self, policy: ReaddirErrorPolicy) -> MountOptions<'a> {
+        self.readdir_errors = policy;
+        self
+    }
+
+    /// Counts the listing entries skipped under `ReaddirErrorPolicy::Skip` in `skipped`
+    pub fn skipped_entries(mut self, skipped: &'a SkippedEntries) -> MountOptions<'a> {
+        self.skipped_entries = Some(skipped);
+        self
+    }
+
+    /// Caches `ENOENT` results of `NetworkFilesystem::lookup` for the given duration
+    ///
+    /// Names created through the filesystem are removed from this cache immediately.
+    /// Disabled by default, though lookups in fully listed directories never reach the network.
+    pub fn negative_ttl(mut self, ttl: Duration) -> MountOptions<'a> {
+        self.negative_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets how names are resolved in directories that have not been listed yet
+    ///
+    /// Overrides the backend's `NetworkFilesystem::lookup_strategy` hint
+    pub fn lookup_strategy(mut self, strategy: LookupStrategy) -> MountOptions<'a> {
+        self.lookup_strategy = Some(strategy);
+        self
+    }
+
+    /// Enables prefetching small files into the data cache in the background
+    ///
+    /// Requires a backend that implements `NetworkFilesystem::clone_for_prefetch`
+    pub fn prefetch(mut self, options: PrefetchOptions) -> MountOptions<'a> {
+        self.prefetch = Some(options);
+        self
+    }
+
+    /// Revalidates cached metadata with `NetworkFilesystem::revalidate` once it is older than `ttl`
+    ///
+    /// Only applies to files whose `Metadata` has a `version`. Without a TTL, metadata is only
+    /// revalidated when opening a file whose data is still cached.
+    pub fn revalidate_ttl(mut self, ttl: Duration) -> MountOptions<'a> {
+        self.revalidate_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets what happens when writing back a file that was changed remotely since it was read
+    ///
+    /// Defaults to `ConflictPolicy::Fail`
+    pub fn conflict_policy(mut self, policy: ConflictPolicy) -> MountOptions<'a> {
+        self.conflict_policy = policy;
+        self
+    }
+
+    /// Persists inode numbers and generations to `path`, so that files keep their inode numbers across mounts
+    ///
+    /// The file is loaded when mounting and rewritten atomically as inodes are added or removed,
+    /// at most every few seconds, and when unmounting.
+    pub fn state_file<P: AsRef<Path>>(mut self, path: &'a P) -> MountOptions<'a> {
+        self.state_file = Some(path.as_ref());
+        self
+    }
+
+    /// Caps the number of cached inodes
+    ///
+    /// Inodes the kernel has forgotten are always evicted once they have no open or unwritten data.
+    /// Beyond that, when the cache grows past `max`, inodes from directory listings that the kernel
+    /// never looked up are evicted too. Evicted entries are fetched from the network again when needed.
+    pub fn max_inodes(mut self, max: usize) -> MountOptions<'a> {
+        self.max_inodes = Some(max);
+        self
+    }
+
+    /// Maps the `owner` and `group` of `Metadata` to local ids
+    ///
+    /// Without a map, every file is owned by the uid and gid the volume is mounted with.
+    /// With one, changing the owner of a file calls `NetworkFilesystem::chown`.
+    pub fn id_map(mut self, id_map: &'a IdMap) -> MountOptions<'a> {
+        self.id_map = Some(id_map);
+        self
+    }
+
+    /// Leaves permission checks to the kernel, based on the mode bits and owners of files
+    ///
+    /// Mounts with the `default_permissions` option, and `NetworkFilesystem::check_access` is not called.
+    /// Disabled by default, so that backends can enforce permissions the mode bits can't express.
+    pub fn default_permissions(mut self, enabled: bool) -> MountOptions<'a> {
+        self.default_permissions = enabled;
+        self
+    }
+
+    /// Includes the command line of the calling process in the `Caller` passed to the backend
+    ///
+    /// Disabled by default, since it is read from `/proc` for every request.
+    pub fn caller_cmdline(mut self, enabled: bool) -> MountOptions<'a> {
+        self.caller_cmdline = enabled;
+        self
+    }
+
+    /// Lets users other than the one mounting the volume access it
+    ///
+    /// Mounts with the `allow_other` option, which usually requires `user_allow_other`
+    /// in `/etc/fuse.conf`. See `PerUserFilesystem` for giving each user their own backend.
+    pub fn allow_other(mut self, enabled: bool) -> MountOptions<'a> {
+        self.allow_other = enabled;
+        self
+    }
+
+    /// Limits how long unmounting spends writing back data that hasn't been written yet
+    ///
+    /// Files are written back one at a time, and once the timeout is over the remaining files
+    /// are reported as lost instead. A write-back already in progress is not interrupted.
+    /// Defaults to 30 seconds.
+    pub fn unmount_timeout(mut self, timeout: Duration) -> MountOptions<'a> {
+        self.unmount_timeout = timeout;
+        self
+    }
+
+    /// Records changes in a write journal in the directory `dir`, and replays them to the backend in the background
+    ///
+    /// Written files, new directories and removals are synced to the journal before they are
+    /// reported as done, so they survive the backend being unreachable and the process restarting:
+    /// changes left in the journal are replayed after mounting again. Changes are replayed in order,
+    /// each retried as long as the backend fails with an error `is_unreachable` accepts. Other failures
+    /// are logged, and the data of failed writes is kept in `dir`. Replayed writes are unconditional,
+    /// so the conflict policy doesn't apply to them.
+    ///
+    /// Until they are replayed, lookups and listings show journaled changes rather than what the
+    /// backend has, and removing a directory fails with `ENOTEMPTY` if it has known children.
+    ///
+    /// Requires a backend that implements `NetworkFilesystem::clone_for_journal`
+    pub fn journal<P: AsRef<Path>>(mut self, dir: &'a P) -> MountOptions<'a> {
+        self.journal = Some(dir.as_ref());
+        self
+    }
+
+    /// Serves lookups, listings and reads from the cache while the backend is unreachable
+    ///
+    /// Disabled by default, so every call is made to the backend and its errors are returned as is.
+    /// Combine with `journal` to keep writes working too. When enabled, the data of closed files
+    /// stays cached up to `OfflineOptions::max_bytes` so that it can be read while offline, and access
+    /// checks fall back to the cached mode bits while the backend can't be asked.
+    pub fn offline(mut self, options: OfflineOptions) -> MountOptions<'a> {
+        self.offline = Some(options);
+        self
+    }
+
+    /// Keeps the paths pinned in `pins` available offline, syncing them in the background while mounted
+    ///
+    /// Pinned files are read from the local copy as long as their version is unchanged, and while
+    /// the backend is offline pinned paths stay readable even if they were never opened.
+    /// Ignored if the backend isolates users.
+    ///
+    /// Requires a backend that implements `NetworkFilesystem::clone_for_prefetch`
+    pub fn pins(mut self, pins: &'a Pins) -> MountOptions<'a> {
+        self.pins = Some(pins);
+        self
+    }
+}
+
+/// How `NetFuse` handles an `Err` returned by the `NetworkFilesystem::readdir` iterator
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ReaddirErrorPolicy {
+    /// Reply to the `readdir` call with the error
+    Abort,
+    /// Log a warning, count the failed entry, and continue with the rest of the listing
+    Skip,
+}
+
+/// Number of listing entries skipped because of errors since mounting
+///
+/// Clones share the count, so a clone passed to `MountOptions::skipped_entries` can be read
+/// while the filesystem is mounted.
+#[derive(Debug, Clone, Default)]
+pub struct SkippedEntries {
+    count: Arc<AtomicU64>,
+}
+
+impl SkippedEntries {
+    pub fn new() -> SkippedEntries {
+        SkippedEntries::default()
+    }
+
+    /// Number of entries skipped so far
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // Counts one more skipped entry, returning the new total
+    fn add(&self) -> u64 {
+        self.count.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// How `NetFuse` handles a conditional `NetworkFilesystem::write` that fails with `ESTALE`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConflictPolicy {
+    /// Keep the local changes in the cache and fail the write-back
+    Fail,
+    /// Write the local changes anyway, replacing the remote changes
+    Overwrite,
+    /// Write the local changes to a sibling `<name>.conflict-<timestamp>` file,
+    /// and drop them from the original file so the remote changes are read next
+    SaveCopy,
 }
 
 /// Low-level FUSE implementation that is backed by an implementation of `NetworkFilesystem`
@@ -62,371 +349,1883 @@ impl <'a> MountOptions<'a> {
 pub struct NetFuse<NFS: NetworkFilesystem> {
     // stores all the metadata and the mapping between inode number and path
     inodes: InodeStore,
-    // map of inodes to to data buffers - indexed by inode (NOT inode-1)
-    cache: HashMap<u64, CacheEntry>,
+    // map of inodes to to data buffers - indexed by inode (NOT inode-1) and the user if isolated
+    cache: HashMap<CacheKey, CacheEntry>,
     // implementor that provides a backend store for the filesystem
     nfs: NFS,
+    // map of open directory handles to the listing still being read from the network
+    dir_handles: HashMap<u64, DirHandle>,
+    // map of open file handles to their state
+    file_handles: HashMap<u64, FileHandle>,
+    // last handle number given out by open or opendir
+    last_fh: u64,
+    // cached data whose write-back failed, with the caller to retry as and when it last failed
+    failed_writes: HashMap<CacheKey, (Caller, Instant)>,
+    // how to handle errors for individual entries of a listing
+    readdir_errors: ReaddirErrorPolicy,
+    // number of listing entries skipped because of errors
+    skipped_entries: SkippedEntries,
+    // how long failed lookups are remembered
+    negative_ttl: Option<Duration>,
+    // how to resolve names in directories that have not been listed
+    lookup_strategy: LookupStrategy,
+    // background reader of small files, if prefetching is enabled
+    prefetcher: Option<Prefetcher>,
+    // prefetched inodes that have not been opened yet, oldest first, with their size
+    prefetched: VecDeque<(u64, u64)>,
+    prefetched_bytes: u64,
+    // clean data of closed files kept to be read while offline, least recently closed first, with its size
+    kept: VecDeque<(CacheKey, u64)>,
+    kept_bytes: u64,
+    // parent and directory cookie of the last opened file, to detect sequential opens
+    last_opened: Option<(u64, u64)>,
+    // age after which versioned metadata is revalidated
+    revalidate_ttl: Option<Duration>,
+    // how to handle remote changes detected when writing back
+    conflict_policy: ConflictPolicy,
+    // number of cached inodes above which unreferenced inodes are evicted
+    max_inodes: Option<usize>,
+    // true if the kernel checks permissions instead of `NetworkFilesystem::check_access`
+    default_permissions: bool,
+    // true if the command line of callers is read for each request
+    caller_cmdline: bool,
+    // true if each user gets their own cached data and directory listings
+    isolate_users: bool,
+    // time allowed for writing back data when unmounting
+    unmount_timeout: Duration,
+    // log of changes waiting to be replayed to the backend, if enabled
+    journal: Option<Journal>,
+    // reachability of the backend, if serving from the cache while offline is enabled
+    health: Option<Health>,
+    // paths kept available offline, if pinning is enabled
+    pins: Option<Pins>,
+    // true once dirty data was written back for unmounting
+    unmounted: bool,
 }
 
-/// Mount the given `NetworkFilesystem`. This function will not return until the filesystem is unmounted.
-pub fn mount<NFS: NetworkFilesystem>(fs: NFS, options: MountOptions) {
-    let netfuse = NetFuse {
-        nfs: fs,
-        inodes: InodeStore::new(0o550, options.uid, options.gid),
-        cache: HashMap::new(),
-    };
-    fuse::mount(netfuse, &options.path, &[]);
+// State of an open directory
+struct DirHandle {
+    ino: u64,
+    // remainder of the network listing, None once exhausted or if served from cache
+    entries: Option<DirIterator>,
+    // children this user listed, if users are isolated
+    visible: Option<HashSet<u64>>,
+    // error that ended the network listing under `ReaddirErrorPolicy::Abort`, returned again by later calls
+    error: Option<LibcError>,
+    // names pulled from the network listing so far
+    listed: HashSet<OsString>,
 }
 
-impl <NFS: NetworkFilesystem> NetFuse<NFS> {
-    fn cache_readdir<'a>(&'a mut self, ino: u64) -> Box<Iterator<Item=Result<(OsString, FileAttr), LibcError>> + 'a> {
-        let iter = self.inodes
-                        .children(ino)
-                        .into_iter()
-                        .map( move |child| {
-                            Ok((get_basename(&child.path).into(), child.attr.clone()))
-                        });
-        Box::new(iter)
+impl DirHandle {
+    fn new(ino: u64, entries: Option<DirIterator>, visible: Option<HashSet<u64>>) -> DirHandle {
+        DirHandle { ino: ino, entries: entries, visible: visible, error: None, listed: HashSet::new() }
     }
+}
 
-    // true if data was written, false if nothing needed written
-    // error if writing failed
-    fn flush_cache_if_needed(&mut self, ino: u64) -> Result<bool, LibcError> {
-        let flushed = {
-            let entry = self.cache.get(&ino).unwrap();
-
-            match entry.warm && !entry.sync {
-                true => {
-                    let ref path = self.inodes[ino].path;
-                    try!(self.nfs.write(&Path::new(&path), &entry.data));
-                    true
-                }
-                false => false
-            }
-        };
-
-        if flushed {
-            // TODO: update attr mtime
-            self.cache.get_mut(&ino).unwrap().sync = true;
-        }
-
-        Ok(flushed)
+impl fmt::Debug for DirHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DirHandle")
+            .field("ino", &self.ino)
+            .field("listing", &self.entries.is_some())
+            .field("visible", &self.visible.as_ref().map(|visible| visible.len()))
+            .field("error", &self.error)
+            .finish()
     }
+}
 
-    fn read_to_cache_if_needed(&mut self, ino: u64) -> Result<bool, LibcError> {
-        // return if cache is already warm
-        if self.cache.get(&ino).unwrap().warm {
-            return Ok(false);
-        }
+// State of an open file
+#[derive(Debug)]
+struct FileHandle {
+    // the process that opened the file
+    caller: Caller,
+    // key of the cached data read and written through this handle
+    key: CacheKey,
+    // flags the file was opened with
+    flags: u32,
+    // version of the file when it was opened
+    version: Option<String>,
+    // true if data written through this handle hasn't been written back yet
+    written: bool,
+    // write-back error for data written through this handle, reported by the next flush
+    error: Option<LibcError>,
+}
 
-        // make request to network backend for data to populate cache
-        let path = Path::new(&self.inodes[ino].path);
-        let mut buffer = Vec::new();
-        let _ = try!(self.nfs.read(&path, &mut buffer));
-        let mut entry = self.cache.get_mut(&ino).unwrap();
-        entry.set(buffer);
-        entry.sync = true;
-        Ok(true)
+impl FileHandle {
+    fn is_readable(&self) -> bool {
+        self.flags as c_int & O_ACCMODE != O_WRONLY
     }
 
+    fn is_writable(&self) -> bool {
+        let mode = self.flags as c_int & O_ACCMODE;
+        mode == O_WRONLY || mode == O_RDWR
+    }
 }
 
-fn get_basename(path: &Path) -> &OsStr {
-    path.file_name().expect("missing filename")
+/// Mount the given `NetworkFilesystem`. This function will not return until the filesystem is unmounted.
+pub fn mount<NFS: NetworkFilesystem>(fs: NFS, options: MountOptions) {
+    let netfuse = NetFuse::new(fs, &options);
+
+    let mut session = fuse::Session::new(netfuse, options.path, &fuse_options(&options));
+    session.run();
+    // The kernel only sends FUSE_DESTROY for fuseblk mounts, so unmounting usually just ends the session
+    session.filesystem.unmount();
 }
 
-impl <NFS: NetworkFilesystem> Filesystem for NetFuse<NFS> {
+/// Mount the given `AsyncNetworkFilesystem`. This function will not return until the filesystem is unmounted.
+///
+/// Requests are served on a thread of their own, leaving the FUSE loop free to read the next request.
+///   Reads of file data that isn't cached yet and lookups of names that aren't cached wait for the backend
+///   on a pool of worker threads, so several of them can be in flight at once without holding up other
+///   requests. Other requests are served one at a time, each waiting for its future.
+pub fn mount_async<A: AsyncNetworkFilesystem + Send + Sync + 'static>(fs: A, options: MountOptions) {
+    let (jobs, queue) = mpsc::channel();
+    thread::scope(|scope| {
+        let server_jobs = jobs.clone();
+        let options = &options;
+        scope.spawn(move || async_fuse::serve(fs, options, server_jobs, queue));
+
+        // Dropping the filesystem when the session ends unmounts it on the serving thread
+        let filesystem = AsyncNetFuse::new(jobs, options.caller_cmdline);
+        fuse::Session::new(filesystem, options.path, &fuse_options(options)).run();
+    });
+}
 
-    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
-        self.nfs.init()
+// Options passed to the FUSE library when mounting
+fn fuse_options(options: &MountOptions) -> Vec<&'static OsStr> {
+    let mut fuse_options: Vec<&OsStr> = Vec::new();
+    if options.default_permissions {
+        fuse_options.push(OsStr::new("-o"));
+        fuse_options.push(OsStr::new("default_permissions"));
     }
+    if options.allow_other {
+        fuse_options.push(OsStr::new("-o"));
+        fuse_options.push(OsStr::new("allow_other"));
+    }
+    fuse_options
+}
 
-    // If parent is marked visited, then only perform lookup in the cache
-    // otherwise, if the cache lookup is a miss, perform the network lookup
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
-        debug!("lookup(parent={}, name=\"{}\")", parent, name.display());
-
-        // Clone until MIR NLL lands
-        match self.inodes.child(parent, &name).cloned() {
-            Some(child_inode) => reply.entry(&DEFAULT_TTL, &child_inode.attr, 0),
-            None => {
-                // Clone until MIR NLL lands
-                let parent_inode = self.inodes[parent].clone();
-                let child_path = parent_inode.path.join(&name);
-                match self.nfs.lookup(&child_path) {
-                    Ok(child_metadata) => {
-                        let inode = self.inodes.insert_metadata(&child_path, &child_metadata);
-                        reply.entry(&DEFAULT_TTL, &inode.attr, 0)
-                    }
-                    Err(err) => reply.error(err),
-                }
+impl <NFS: NetworkFilesystem> NetFuse<NFS> {
+    // Sets up the filesystem for `fs` as configured by `options`, starting its background workers
+    fn new(fs: NFS, options: &MountOptions) -> NetFuse<NFS> {
+        let lookup_strategy = options.lookup_strategy.unwrap_or_else(|| fs.lookup_strategy());
+        let isolate_users = fs.isolate_users();
+        let prefetcher = match isolate_users {
+            true => None,
+            false => options.prefetch.and_then(|prefetch| Prefetcher::start(&fs, prefetch)),
+        };
+        let mut inodes = InodeStore::new(0o550, options.uid, options.gid);
+        if let Some(id_map) = options.id_map {
+            inodes.set_id_map(id_map.clone());
+        }
+        let journal = options.journal.and_then(|dir| match Journal::open(dir, &fs, JOURNAL_RETRY_INTERVAL) {
+            Ok(Some(journal)) => Some(journal),
+            Ok(None) => {
+                warn!("journal disabled - the backend doesn't implement clone_for_journal");
+                None
+            }
+            Err(err) => {
+                error!("failed to open journal in {} - {}", dir.display(), err);
+                None
+            }
+        });
+        let pins = options.pins.and_then(|pins| {
+            if isolate_users {
+                warn!("pinning disabled - the backend isolates users");
+                None
+            } else if !pins.start(&fs) {
+                warn!("pinning disabled - the backend doesn't implement clone_for_prefetch");
+                None
+            } else {
+                Some(pins.clone())
+            }
+        });
+        if let Some(state_file) = options.state_file {
+            if let Err(err) = inodes.load_state(state_file) {
+                error!("failed to load inode state from {} - {}", state_file.display(), err);
             }
         }
+
+        NetFuse {
+            nfs: fs,
+            inodes: inodes,
+            cache: HashMap::new(),
+            dir_handles: HashMap::new(),
+            file_handles: HashMap::new(),
+            last_fh: 0,
+            failed_writes: HashMap::new(),
+            readdir_errors: options.readdir_errors,
+            skipped_entries: options.skipped_entries.cloned().unwrap_or_default(),
+            negative_ttl: options.negative_ttl,
+            lookup_strategy: lookup_strategy,
+            prefetcher: prefetcher,
+            prefetched: VecDeque::new(),
+            prefetched_bytes: 0,
+            kept: VecDeque::new(),
+            kept_bytes: 0,
+            last_opened: None,
+            revalidate_ttl: options.revalidate_ttl,
+            conflict_policy: options.conflict_policy,
+            max_inodes: options.max_inodes,
+            default_permissions: options.default_permissions,
+            caller_cmdline: options.caller_cmdline,
+            isolate_users: isolate_users,
+            unmount_timeout: options.unmount_timeout,
+            journal: journal,
+            health: options.offline.map(Health::new),
+            pins: pins,
+            unmounted: false,
+        }
     }
 
-    // Return the cached inode
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        match self.inodes.get(ino) {
-            Some(inode) => reply.attr(&DEFAULT_TTL, &inode.attr),
-            None => {
-                info!("getattr ENOENT: {}", ino);
-                reply.error(ENOENT);
-            }
-        };
+    // Writes the inode state file if inodes changed, at most once per save interval unless forced
+    fn persist_inodes(&mut self, force: bool) {
+        if let Err(err) = self.inodes.save_state(force, STATE_SAVE_INTERVAL) {
+            error!("failed to save inode state - {}", err);
+        }
     }
 
-    // If the data cache for this ino not warm, call the network read to populated the cache
-    // then use the offset and size to return the right part of the cached data
-    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, size: u32, reply: ReplyData) {
-        debug!("read(ino={}, fh={}, offset={}, size={})", ino, _fh, offset, size);
+    // Key of the data of `ino` cached for `caller`
+    fn cache_key(&self, caller: &Caller, ino: u64) -> CacheKey {
+        match self.isolate_users {
+            true => (ino, Some(caller.uid)),
+            false => (ino, None),
+        }
+    }
 
-        // Determine if we should hit the API
-        if let Err(err) = self.read_to_cache_if_needed(ino) {
-            return reply.error(err);
+    // Keys of the data of `ino` cached for any user
+    fn cache_keys(&self, ino: u64) -> Vec<CacheKey> {
+        match self.isolate_users {
+            true => self.cache.keys().filter(|&&(cached, _)| cached == ino).cloned().collect(),
+            false => Some((ino, None)).into_iter().filter(|key| self.cache.contains_key(key)).collect(),
         }
+    }
 
-        // Return the cached data
-        let ref buffer = self.cache.get(&ino).unwrap().data;
-        let end_offset = offset + size as u64;
-        match buffer.len() {
-            len if len as u64 > offset + size as u64 => {
-                reply.data(&buffer[(offset as usize)..(end_offset as usize)]);
-            }
-            len if len as u64 > offset => {
-                reply.data(&buffer[(offset as usize)..]);
-            }
-            len => {
-                error!("attempted read beyond buffer for ino {} len={} offset={} size={}", ino, len, offset, size);
-                reply.error(ENOENT);
-            }
+    fn is_cached(&self, ino: u64) -> bool {
+        match self.isolate_users {
+            true => self.cache.keys().any(|&(cached, _)| cached == ino),
+            false => self.cache.contains_key(&(ino, None)),
         }
     }
 
-    // TODO: properly support offset
-    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, mut reply: ReplyDirectory) {
-        debug!("readdir(ino={}, fh={}, offset={})", ino, _fh, offset);
-        if offset > 0 {
-            reply.ok();
-            return;
+    // Last journaled change to `path` that the backend doesn't know about yet
+    fn pending_op(&self, path: &Path) -> Option<JournalOp> {
+        self.journal.as_ref().and_then(|journal| journal.pending_op(path))
+    }
+
+    // true if `ino` has journaled changes, which only the inode store knows about until they are replayed
+    fn is_journaled(&self, ino: u64) -> bool {
+        self.inodes.get(ino).map(|inode| self.pending_op(&inode.path).is_some()).unwrap_or(false)
+    }
+
+    // Inserts `path` as it is once its journaled creation `op` is replayed, unless it is cached.
+    // Returns None if `op` doesn't create it.
+    fn insert_journaled(&mut self, path: &Path, op: JournalOp) -> Option<u64> {
+        if let Some(inode) = self.inodes.get_by_path(path) {
+            return Some(inode.attr.ino);
+        }
+        let metadata = match op {
+            JournalOp::Mkdir => local_metadata(FileType::Directory, 0o755, 0),
+            JournalOp::Write => {
+                let size = self.journal.as_ref()
+                    .and_then(|journal| journal.pending_data(path))
+                    .and_then(|data| data.ok())
+                    .map(|data| data.len() as u64)
+                    .unwrap_or(0);
+                local_metadata(FileType::RegularFile, 0o644, size)
+            }
+            JournalOp::Rmdir | JournalOp::Unlink => return None,
+        };
+        let ino = self.inodes.insert_metadata(path, &metadata).attr.ino;
+        // a journaled directory can't be listed from the backend before it's replayed
+        if op == JournalOp::Mkdir {
+            self.inodes.set_visited(ino, true);
         }
+        Some(ino)
+    }
 
-        let parent_ino = match ino {
-            1 => 1,
-            _ => self.inodes.parent(ino).expect("inode has no parent").attr.ino,
+    // Inserts the children of the directory `ino` created by journaled changes,
+    // returning their inode numbers
+    fn insert_journaled_children(&mut self, ino: u64) -> Vec<u64> {
+        let path = self.inodes[ino].path.clone();
+        let children = match self.journal {
+            Some(ref journal) => journal.pending_children(&path),
+            None => return Vec::new(),
         };
+        children.into_iter().filter_map(|(child, op)| self.insert_journaled(&child, op)).collect()
+    }
 
-        reply.add(ino, 0, FileType::Directory, ".");
-        reply.add(parent_ino, 1, FileType::Directory, "..");
+    // A journaled rmdir that fails when replayed is lost, so the directory `path` (inode `ino`, if cached)
+    // must be empty as far as is known locally before journaling its removal
+    fn check_journaled_rmdir(&self, ino: Option<u64>, path: &Path) -> Result<(), LibcError> {
+        let journal = match self.journal {
+            Some(ref journal) => journal,
+            None => return Ok(()),
+        };
+        let cached = ino.map(|ino| !self.inodes.children(ino).is_empty()).unwrap_or(false);
+        let journaled = journal.pending_children(path).iter()
+            .any(|&(_, op)| op == JournalOp::Write || op == JournalOp::Mkdir);
+        match cached || journaled {
+            true => Err(ENOTEMPTY),
+            false => Ok(()),
+        }
+    }
 
-        let dir_visited  = self.inodes.get(ino).map(|n| n.visited).unwrap_or(false);
-        match dir_visited {
-            // read directory from cache
-            true =>  {
-                for (i, next) in self.cache_readdir(ino).enumerate().skip(offset as usize) {
-                    match next {
-                        Ok((filename, attr)) => {
-                            reply.add(attr.ino, i as u64 + offset + 2, attr.kind, &filename);
-                        }
-                        Err(err) => { return reply.error(err); }
+    // Called after inodes were added or removed to enforce the inode cap and persist the change.
+    // Children of directories with a network listing in progress are kept, since the listing
+    // won't return them again before the directory is marked visited.
+    fn inodes_changed(&mut self) {
+        if let Some(max_inodes) = self.max_inodes {
+            if self.inodes.len() > max_inodes {
+                // evict down to 90% of the cap, so eviction doesn't run on every insert
+                let target = max_inodes - max_inodes / 10;
+                let listing: HashSet<u64> = self.dir_handles.values()
+                    .filter(|handle| handle.entries.is_some())
+                    .map(|handle| handle.ino)
+                    .collect();
+                for ino in self.inodes.unreferenced() {
+                    if self.inodes.len() <= target {
+                        break;
                     }
-                }
-            },
-            // read directory from cache
-            false => {
-                // FIXME: sometimes cloning is just easier than fixing borrows
-                let ref parent_path = self.inodes[ino].path.clone();
-                let ref mut nfs = self.nfs;
-                for (i, next) in nfs.readdir(&parent_path).into_iter().enumerate().skip(offset as usize) {
-                    match next {
-                        Ok(entry) => {
-                            let child_path = parent_path.join(&entry.filename);
-                            let inode = self.inodes.insert_metadata(&child_path, &entry.metadata);
-                            reply.add(inode.attr.ino, i as u64 + offset + 2, inode.attr.kind, &entry.filename);
-                        }
-                        Err(err) => { return reply.error(err); }
+                    let being_listed = self.inodes.parent(ino).map(|parent| listing.contains(&parent.attr.ino)).unwrap_or(false);
+                    if !self.is_cached(ino) && !self.is_journaled(ino) && !being_listed {
+                        self.inodes.evict(ino);
                     }
                 }
+                debug!("evicted inodes down to {} (max {})", self.inodes.len(), max_inodes);
             }
-        };
-
-        // Mark this node visited
-        let ref mut inodes = self.inodes;
-        let mut dir_inode = inodes.get_mut(ino).expect("inode missing for dir just listed");
-        dir_inode.visited = true;
+        }
+        self.persist_inodes(false);
+    }
 
-        reply.ok();
+    // Identifies the process that made `req`
+    fn caller(&self, req: &Request) -> Caller {
+        request_caller(req, self.caller_cmdline)
     }
 
-    fn mknod(&mut self, _req: &Request, parent: u64, name: &Path, _mode: u32, _rdev: u32, reply: ReplyEntry) {
-        debug!("mknod(parent={}, name={}, mode=0o{:o})", parent, name.display(), _mode);
+    // false while the backend is offline, except when a probe for recovery is due
+    fn backend_available(&mut self) -> bool {
+        self.health.as_mut().map(|health| health.should_try()).unwrap_or(true)
+    }
 
-        // TODO: check if we have write access to this parent (or does the FS do that for us)
-        // or maybe some `self.nfs.allow_mknod(&path)
+    // Tracks the health of the backend from the result of a call to it
+    fn record_call<T>(&mut self, result: &Result<T, LibcError>) {
+        if let Some(ref mut health) = self.health {
+            health.record(result.as_ref().err().cloned());
+        }
+    }
 
-        let path = self.inodes[parent].path.join(&name);
-        let now = time::now_utc().to_timespec();
+    // Asks the backend whether `caller` may access `path`, unless the kernel checks permissions.
+    // While the backend is unreachable, the cached mode bits decide instead.
+    fn check_access(&mut self, caller: &Caller, path: &Path, mode: c_int) -> Result<(), LibcError> {
+        if self.default_permissions {
+            return Ok(());
+        }
+        let answer = match self.backend_available() {
+            true => {
+                let result = self.nfs.check_access(caller, path, mode as u32);
+                self.record_call(&result);
+                Some(result)
+            }
+            false => None,
+        };
+        let result = match answer {
+            Some(Err(err)) if self.health.is_some() && is_unreachable(err) => None,
+            answer => answer,
+        };
+        let result = result.unwrap_or_else(|| match self.inodes.get_by_path(path) {
+            Some(inode) => check_mode(&inode.attr, caller, mode),
+            None => Err(EIO),
+        });
+        result.inspect_err(|err| {
+            info!("access to {} denied for uid {} (mode {}) - {}", path.display(), caller.uid, mode, err);
+        })
+    }
 
-        let meta = Metadata {
-            size: 0,
-            atime: now,
-            mtime: now,
-            ctime: now,
-            crtime: now,
-            kind: FileType::RegularFile,
-            perm: _mode as u16,  // TODO: should this be based on _mode or parent -x bits (e.g. & 0o666)
+    // Changes the owner of `ino` in the backend, mapping the local ids back to remote names
+    fn chown(&mut self, caller: &Caller, ino: u64, uid: Option<u32>, gid: Option<u32>) -> Result<(), LibcError> {
+        let path = self.inodes.get(ino).ok_or(ENOENT)?.path.clone();
+        let (owner, group) = {
+            let id_map = self.inodes.id_map().expect("chown without an id map");
+            let owner = match uid {
+                Some(uid) => Some(id_map.user_for_uid(uid).ok_or(EPERM)?.to_owned()),
+                None => None,
+            };
+            let group = match gid {
+                Some(gid) => Some(id_map.group_for_gid(gid).ok_or(EPERM)?.to_owned()),
+                None => None,
+            };
+            (owner, group)
         };
+        self.nfs.chown(caller, &path, owner.as_deref(), group.as_deref())
+    }
 
-        // FIXME: cloning because it's quick-and-dirty
-        let attr = self.inodes.insert_metadata(&Path::new(&path), &meta).attr.clone();
+    // Truncates `ino` to `size`. While the file is open for writing, only the cached data is truncated,
+    // and written back with whatever is written next when a handle is flushed: opening with O_TRUNC
+    // truncates with a separate call, which mustn't replace the remote file with an empty one.
+    // Otherwise the truncated file is written back right away.
+    fn truncate(&mut self, caller: &Caller, ino: u64, fh: Option<u64>, size: u64) -> Result<(), LibcError> {
+        let (key, version) = match fh.and_then(|fh| self.file_handles.get(&fh)) {
+            Some(handle) => (handle.key, handle.version.clone()),
+            None => (self.cache_key(caller, ino), self.inodes.get(ino).ok_or(ENOENT)?.version.clone()),
+        };
+        self.truncate_cache(caller, key, size, version)?;
 
-        // Need to add an entry and declare it warm, so that empty files can be created on release/fsync
-        //   but don't increment opened handles until `open` is called
-        let mut entry = self.cache.entry(attr.ino).or_insert_with(|| CacheEntry::new());
-        entry.warm = true;
+        let mut open_for_writing = false;
+        for handle in self.file_handles.values_mut().filter(|handle| handle.key == key && handle.is_writable()) {
+            handle.written = true;
+            open_for_writing = true;
+        }
+        if open_for_writing {
+            return Ok(());
+        }
 
-        // TODO: figure out when/if I should be using a generation number:
-        //       https://github.com/libfuse/libfuse/blob/842b59b996e3db5f92011c269649ca29f144d35e/include/fuse_lowlevel.h#L78-L91
-        reply.entry(&DEFAULT_TTL, &attr, 0);
+        let result = self.write_back(caller, key);
+        if result.is_ok() {
+            self.purge_closed(key);
+        }
+        result.map(|_| ())
     }
 
-    fn mkdir(&mut self, _req: &Request, parent: u64, name: &Path, _mode: u32, reply: ReplyEntry) {
-        debug!("mkdir(parent={}, name={}, mode=0o{:o})", parent, name.display(), _mode);
-
-        let path = self.inodes[parent].path.join(&name);
-        match self.nfs.mkdir(&path) {
-            Ok(_) => {
-                let now = time::now_utc().to_timespec();
-                let meta = Metadata {
-                    size: 0,
-                    atime: now,
-                    mtime: now,
-                    ctime: now,
-                    crtime: now,
-                    kind: FileType::Directory,
-                    perm: _mode as u16,  // TODO: should this be based on _mode or parent
-                };
-
-                let attr = self.inodes.insert_metadata(&path, &meta).attr;
+    // Replies with the entry for `ino`, counting the reference the kernel takes on it
+    fn reply_entry(&mut self, ino: u64, reply: ReplyEntry) {
+        let inode = self.inodes.add_lookup(ino);
+        reply.entry(&DEFAULT_TTL, &inode.attr, inode.generation);
+    }
 
-                // TODO: figure out when/if I should be using a generation number:
-                //       https://github.com/libfuse/libfuse/blob/842b59b996e3db5f92011c269649ca29f144d35e/include/fuse_lowlevel.h#L78-L91
-                reply.entry(&DEFAULT_TTL, &attr, 0);
-            }
-            Err(err) => {
-                error!("mkdir error - {}", err);
-                reply.error(err);
+    // Applies the readdir error policy to an error for a single entry of the listing at `path`
+    fn skip_entry_error(&mut self, path: &Path, err: LibcError) -> Result<(), LibcError> {
+        match self.readdir_errors {
+            ReaddirErrorPolicy::Abort => Err(err),
+            ReaddirErrorPolicy::Skip => {
+                let skipped = self.skipped_entries.add();
+                warn!("readdir skipping entry of {} - error {} ({} entries skipped since mount)",
+                      path.display(), err, skipped);
+                Ok(())
             }
         }
     }
 
-    fn open (&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        debug!("open(ino={}, flags=0x{:x})", ino, flags);
-        // match flags & O_ACCMODE => O_RDONLY, O_WRONLY, O_RDWR
+    // Lists the entire directory from the network into the inode store and marks it visited.
+    // Returns the inode numbers of the listed children.
+    fn list_to_cache(&mut self, caller: &Caller, ino: u64) -> Result<HashSet<u64>, LibcError> {
+        let path = self.inodes[ino].path.clone();
+        if !self.backend_available() {
+            return Err(EIO);
+        }
+        let mut listed = HashSet::new();
+        let mut names = HashSet::new();
+        for next in self.nfs.readdir(caller, &path) {
+            self.record_call(&next);
+            match next {
+                Ok(entry) => {
+                    // the backend doesn't know about journaled changes yet
+                    let child_path = path.join(&entry.filename);
+                    if self.pending_op(&child_path).is_none() {
+                        let child = self.inodes.insert_metadata(&child_path, &entry.metadata).attr.ino;
+                        self.mark_visible(caller, child);
+                        listed.insert(child);
+                        names.insert(entry.filename);
+                    }
+                }
+                Err(err) => self.skip_entry_error(&path, err)?,
+            }
+        }
+        for child in self.insert_journaled_children(ino) {
+            listed.insert(child);
+            names.insert(get_basename(&self.inodes[child].path).to_owned());
+        }
 
-        let mut entry = self.cache.entry(ino).or_insert_with(|| CacheEntry::new());
-        entry.opened();
-        reply.opened(0, flags);
+        self.inodes.drop_unlisted_aliases(ino, &names);
+        self.inodes.set_visited(ino, true);
+        self.prefetch_children(caller, ino, 0, usize::MAX);
+        Ok(listed)
     }
 
-    fn release (&mut self, _req: &Request, ino: u64, fh: u64, flags: u32, _lock_owner: u64, flush: bool, reply: ReplyEmpty) {
-        debug!("release(ino={}, fh={}, flags=0x{:x}, flush={})", ino, fh, flags, flush);
-
-        let handles = self.cache.get_mut(&ino).unwrap().released();
+    // Queues small files among the children of `ino` listed after `cookie` for prefetching,
+    // up to `limit` files or until the prefetch byte budget is used up
+    fn prefetch_children(&mut self, caller: &Caller, ino: u64, cookie: u64, limit: usize) {
+        let prefetcher = match self.prefetcher {
+            Some(ref mut prefetcher) => prefetcher,
+            None => return,
+        };
 
-        // Until a delayed commit is working, also write-on-close
-        if handles == 0 {
-            if let Err(err) = self.flush_cache_if_needed(ino) {
-                error!("release flush error - {}", err);
+        let mut queued = 0;
+        for (_, child) in self.inodes.children_after(ino, cookie) {
+            if queued == limit {
+                break;
+            }
+            let size = child.attr.size;
+            if child.attr.kind != FileType::RegularFile || size > prefetcher.options.max_file_size
+                || self.cache.contains_key(&(child.attr.ino, None)) || prefetcher.is_pending(child.attr.ino) {
+                continue;
             }
+            if self.prefetched_bytes + prefetcher.pending_bytes() + size > prefetcher.options.max_bytes {
+                break;
+            }
+            prefetcher.queue(child.attr.ino, child.path.clone(), size, caller);
+            queued += 1;
         }
+    }
 
-        let &CacheEntry {sync, warm, ..} = self.cache.get(&ino).unwrap();
-        if handles == 0 && (sync || !warm) {
-            info!("release is purging {} from cache", ino);
-            let _ = self.cache.remove(&ino);
+    // Moves finished prefetches into the data cache, first waiting for `ino` if it is being prefetched.
+    // Then evicts the oldest unopened prefetched data that is over the prefetch byte budget.
+    fn collect_prefetched(&mut self, ino: Option<u64>) {
+        loop {
+            let next = match self.prefetcher {
+                Some(ref mut prefetcher) => {
+                    let wait = ino.map(|ino| prefetcher.is_pending(ino)).unwrap_or(false);
+                    prefetcher.next_result(wait)
+                }
+                None => return,
+            };
+
+            match next {
+                Some((done_ino, Ok(data))) => {
+                    // Keep the data unless the file was removed or already read in the meantime
+                    if self.inodes.get(done_ino).is_none() {
+                        continue;
+                    }
+                    let entry = self.cache.entry((done_ino, None)).or_insert_with(CacheEntry::new);
+                    if entry.warm {
+                        continue;
+                    }
+                    let size = data.len() as u64;
+                    entry.set(data);
+                    entry.sync = true;
+                    entry.version = self.inodes[done_ino].version.clone();
+                    if !entry.is_open() {
+                        self.prefetched.push_back((done_ino, size));
+                        self.prefetched_bytes += size;
+                    }
+                }
+                Some((done_ino, Err(err))) => info!("prefetch of {} failed - {}", done_ino, err),
+                None => break,
+            }
         }
 
-        reply.ok();
+        let max_bytes = self.prefetcher.as_ref().map(|p| p.options.max_bytes).unwrap_or(0);
+        while self.prefetched_bytes > max_bytes {
+            let (evicted, size) = self.prefetched.pop_front().expect("prefetched bytes without prefetched inodes");
+            info!("prefetch evicting {} from cache", evicted);
+            self.cache.remove(&(evicted, None));
+            self.prefetched_bytes -= size;
+        }
     }
 
-    fn fsync (&mut self, _req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
-        debug!("fsync(ino={}, fh={}, datasync={})", ino, fh, datasync);
+    // Stops tracking prefetched data for `ino` once the file is opened or removed
+    fn claim_prefetched(&mut self, ino: u64) {
+        if let Some(pos) = self.prefetched.iter().position(|&(prefetched, _)| prefetched == ino) {
+            let (_, size) = self.prefetched.remove(pos).unwrap();
+            self.prefetched_bytes -= size;
+        }
+    }
 
-        match self.flush_cache_if_needed(ino) {
-            Ok(_) => reply.ok(),
-            Err(err) => {
-                error!("fsync error - {}", err);
-                reply.error(EIO);
+    // Pulls entries from a network listing into the inode store, recording their names in `listed`,
+    // and adds those after `last_cookie` to the reply. Entries listed earlier from the inode store are skipped.
+    // true if the listing was exhausted, false if the reply filled up first
+    fn add_listing_entries(&mut self, ino: u64, entries: &mut DirIterator, listed: &mut HashSet<OsString>, mut last_cookie: u64,
+                           add: &mut dyn FnMut(u64, u64, FileType, &OsStr) -> bool) -> Result<bool, LibcError> {
+        // FIXME: sometimes cloning is just easier than fixing borrows
+        let parent_path = self.inodes[ino].path.clone();
+        for next in entries {
+            self.record_call(&next);
+            match next {
+                Ok(entry) => {
+                    let child_path = parent_path.join(&entry.filename);
+                    // journaled changes are listed from the inode store, as the backend doesn't know about them yet
+                    match self.pending_op(&child_path) {
+                        Some(JournalOp::Rmdir) | Some(JournalOp::Unlink) => continue,
+                        Some(_) => {
+                            listed.insert(entry.filename);
+                            continue;
+                        }
+                        None => { listed.insert(entry.filename.clone()); }
+                    }
+                    let inode = self.inodes.insert_metadata(&child_path, &entry.metadata);
+                    if inode.cookie <= last_cookie {
+                        continue;
+                    }
+                    // a full reply leaves the entry in the inode store for the next call
+                    if add(inode.attr.ino, inode.cookie, inode.attr.kind, &entry.filename) {
+                        return Ok(false);
+                    }
+                    last_cookie = inode.cookie;
+                }
+                Err(err) => self.skip_entry_error(&parent_path, err)?,
             }
         }
+        Ok(true)
     }
 
-    fn write (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, data: &[u8], flags: u32, reply: ReplyWrite) {
-        // TODO: check if in read-only mode: EROFS
-        debug!("write(ino={}, fh={}, offset={}, len={}, flags=0x{:x})", ino, fh, offset, data.len(), flags);
-
-        let is_replace = (offset == 0) && (self.inodes.get(ino).unwrap().attr.size < data.len() as u64);
+    // true if versioned metadata of `ino` is older than the revalidation TTL
+    fn is_expired(&self, ino: u64) -> bool {
+        match (self.inodes.get(ino), self.revalidate_ttl) {
+            (Some(inode), Some(ttl)) => inode.version.is_some() && inode.validated.elapsed() >= ttl,
+            _ => false,
+        }
+    }
 
-        // Skip data lookup if write entirely replaces file or if we already cached the API response.
-        if !is_replace {
-            // Determine if we should hit the API
-            if let Err(err) = self.read_to_cache_if_needed(ino) {
-                return reply.error(err);
-            }
+    // Checks the cached version of `ino` with the backend, updating the cached metadata if it changed
+    // and discarding cached data without local changes. Returns ENOENT if the file is gone.
+    // Backends that can't revalidate, and failed revalidations, keep what is cached.
+    fn revalidate(&mut self, caller: &Caller, ino: u64) -> Result<(), LibcError> {
+        let (path, version) = match self.inodes.get(ino) {
+            Some(&Inode { ref path, version: Some(ref version), .. }) => (path.clone(), version.clone()),
+            Some(_) => return Ok(()),
+            None => return Err(ENOENT),
+        };
+        // the backend doesn't know about journaled changes yet
+        if self.journal.as_ref().map(|journal| journal.is_pending(&path)).unwrap_or(false) {
+            return Ok(());
         }
 
-        let new_size = match self.cache.get_mut(&ino) {
-            Some(ref mut entry) => {
-                let end = data.len() + offset as usize;
-                if end > self.inodes[ino].attr.size as usize {
-                    entry.data.resize(end, 0);
+        if !self.backend_available() {
+            return Ok(());
+        }
+        let result = self.nfs.revalidate(caller, &path, &version);
+        self.record_call(&result);
+        let revalidation = match result {
+            Ok(revalidation) => revalidation,
+            Err(err) => {
+                if err != ENOSYS {
+                    info!("revalidate {} failed - {}", path.display(), err);
                 }
-                entry.write(offset, &data);
-                reply.written(data.len() as u32);
-                entry.data.len() as u64
-            }
-            None => {
-                error!("write failed to read file");
-                reply.error(ENOENT);
-                return;
+                return Ok(());
             }
         };
 
-        let ref mut inode = self.inodes[ino];
-        inode.attr.size = new_size;
-    }
+        let keys = self.cache_keys(ino);
+        let open = keys.iter().any(|key| self.cache[key].is_open());
+        let dirty = keys.iter().any(|key| self.cache[key].warm && !self.cache[key].sync);
 
-    fn setattr (&mut self, _req: &Request, ino: u64, _mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, flags:               Option<u32>, reply: ReplyAttr) {
-        debug!("setattr(ino={}, mode={:?}, size={:?}, fh={:?}, flags={:?})", ino, _mode, size, _fh, flags);
-        match self.inodes.get_mut(ino) {
-            Some(mut inode) => {
-                if let Some(new_size) = size {
-                    inode.attr.size = new_size;
+        match revalidation {
+            Revalidation::Unchanged => {
+                self.inodes[ino].validated = Instant::now();
+                Ok(())
+            }
+            Revalidation::Changed(metadata) => {
+                info!("revalidate found {} changed", path.display());
+                let kind = metadata.kind;
+                self.inodes.insert_metadata(&path, &metadata);
+                if kind == FileType::Directory {
+                    self.inodes.set_visited(ino, false);
                 }
-                if let Some(new_uid) = uid {
-                    inode.attr.uid = new_uid;
+                self.claim_prefetched(ino);
+                for key in keys {
+                    let entry = self.cache.get_mut(&key).unwrap();
+                    if entry.warm && !entry.sync {
+                        continue;
+                    }
+                    match entry.is_open() {
+                        true => entry.discard(),
+                        false => { self.cache.remove(&key); }
+                    }
                 }
-                if let Some(new_gid) = gid {
-                    inode.attr.gid = new_gid;
+                Ok(())
+            }
+            Revalidation::Gone => {
+                info!("revalidate found {} gone", path.display());
+                if !open && !dirty {
+                    self.claim_prefetched(ino);
+                    for key in keys {
+                        self.cache.remove(&key);
+                    }
+                    self.inodes.remove_tree(ino);
                 }
-                // TODO: is mode (u32) equivalent to attr.perm (u16)?
-                reply.attr(&DEFAULT_TTL, &inode.attr);
+                Err(ENOENT)
             }
-            None => reply.error(ENOENT)
         }
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEmpty) {
-        debug!("rmdir(parent={}, name={})", parent, name.display());
+    // true if data was written, false if nothing needed written
+    // error if writing failed
+    //
+    // The write is conditional on the version the cached data was read from,
+    // and the conflict policy decides what happens if the file changed remotely since
+    fn flush_cache_if_needed(&mut self, caller: &Caller, key: CacheKey) -> Result<bool, LibcError> {
+        let ino = key.0;
+        let (data, version) = match self.cache.get(&key) {
+            Some(entry) if entry.warm && !entry.sync => (entry.data.clone(), entry.version.clone()),
+            _ => return Ok(false),
+        };
+
+        // data of a file removed while open has nowhere to go
+        let path = match self.inodes.get(ino) {
+            Some(inode) => inode.path.clone(),
+            None => return Ok(false),
+        };
+
+        // Journaled writes are replayed unconditionally, so the conflict policy doesn't apply to them
+        if let Some(ref mut journal) = self.journal {
+            journal.write(caller, &path, &data).map_err(|err| {
+                error!("failed to journal write of {} - {}", path.display(), err);
+                EIO
+            })?;
+            self.cache.get_mut(&key).unwrap().sync = true;
+            return Ok(true);
+        }
+
+        let result = self.nfs.write(caller, &path, &data, version.as_deref());
+        self.record_call(&result);
+        let new_version = match result {
+            Err(ESTALE) => {
+                warn!("write conflict - {} changed since it was read", path.display());
+                match self.conflict_policy {
+                    ConflictPolicy::Fail => return Err(ESTALE),
+                    ConflictPolicy::Overwrite => {
+                        let result = self.nfs.write(caller, &path, &data, None);
+                        self.record_call(&result);
+                        result?
+                    }
+                    ConflictPolicy::SaveCopy => {
+                        self.save_conflict_copy(caller, key, &data)?;
+                        return Ok(true);
+                    }
+                }
+            }
+            result => result?,
+        };
+
+        // TODO: update attr mtime
+        let entry = self.cache.get_mut(&key).unwrap();
+        entry.sync = true;
+        entry.version = new_version.clone();
+        let inode = &mut self.inodes[ino];
+        inode.version = new_version;
+        inode.validated = Instant::now();
+
+        Ok(true)
+    }
+
+    // Writes back the cached data for `key` like `flush_cache_if_needed`, then settles the open handles
+    // that wrote to it: on success they have nothing left to write, on failure they record the error.
+    // Failed write-backs keep the data in the cache and are retried later.
+    fn write_back(&mut self, caller: &Caller, key: CacheKey) -> Result<bool, LibcError> {
+        let result = self.flush_cache_if_needed(caller, key);
+        match result {
+            Ok(_) => { self.failed_writes.remove(&key); }
+            Err(_) => { self.failed_writes.insert(key, (caller.clone(), Instant::now())); }
+        }
+        for handle in self.file_handles.values_mut().filter(|handle| handle.key == key && handle.written) {
+            match result {
+                Ok(_) => handle.written = false,
+                Err(err) => { handle.error.get_or_insert(err); }
+            }
+        }
+        result
+    }
+
+    // Caller to write back the data for `key` as when there is no request to take it from:
+    // the caller of the failed write-back, the opener of a handle that wrote the data,
+    // or else the owner of the mount
+    fn writer(&self, key: CacheKey) -> Caller {
+        if let Some((caller, _)) = self.failed_writes.get(&key) {
+            return caller.clone();
+        }
+        if let Some(handle) = self.file_handles.values().find(|handle| handle.key == key && handle.written) {
+            return handle.caller.clone();
+        }
+        let root = &self.inodes[1].attr;
+        Caller::new(key.1.unwrap_or(root.uid), root.gid, 0)
+    }
+
+    // Makes a change to the backend, or records it in the journal to be replayed in the background
+    fn apply_change(&mut self, op: JournalOp, caller: &Caller, path: &Path) -> Result<(), LibcError> {
+        match self.journal {
+            Some(ref mut journal) => journal.change(op, caller, path).map_err(|err| {
+                error!("failed to journal {} - {}", path.display(), err);
+                EIO
+            }),
+            None => match op {
+                JournalOp::Mkdir => self.nfs.mkdir(caller, path),
+                JournalOp::Rmdir => self.nfs.rmdir(caller, path),
+                JournalOp::Unlink => self.nfs.unlink(caller, path),
+                JournalOp::Write => unreachable!("writes are journaled with their data"),
+            },
+        }
+    }
+
+    // Updates cached versions with the results of replayed journal writes
+    fn collect_journal(&mut self) {
+        loop {
+            let (entry, result) = match self.journal.as_mut().and_then(|journal| journal.next_result()) {
+                Some(next) => next,
+                None => return,
+            };
+            let version = match (entry.op, result) {
+                (JournalOp::Write, Ok(version)) => version,
+                _ => continue,
+            };
+            if self.journal.as_ref().unwrap().is_pending(&entry.path) {
+                continue;
+            }
+            if let Some(ino) = self.inodes.get_by_path(&entry.path).map(|inode| inode.attr.ino) {
+                for key in self.cache_keys(ino) {
+                    let entry = self.cache.get_mut(&key).unwrap();
+                    if entry.sync {
+                        entry.version = version.clone();
+                    }
+                }
+                let inode = &mut self.inodes[ino];
+                inode.version = version;
+                inode.validated = Instant::now();
+            }
+        }
+    }
+
+    // Drops the written back data of `key` from the cache unless the file is open,
+    // or clean data is kept to be read while offline
+    fn purge_closed(&mut self, key: CacheKey) {
+        let open = self.cache.get(&key).map(|entry| entry.is_open()).unwrap_or(false);
+        if !open {
+            self.drop_clean(key);
+        }
+    }
+
+    // Drops the clean data of the closed file `key` from the cache, unless it is kept to be read
+    // while offline. Then evicts the kept data of the files closed longest ago that is over the offline byte budget.
+    fn drop_clean(&mut self, key: CacheKey) {
+        let max_bytes = match self.health {
+            Some(ref health) => health.options().max_bytes,
+            None => {
+                self.cache.remove(&key);
+                return;
+            }
+        };
+
+        self.claim_kept(key);
+        if let Some(entry) = self.cache.get(&key) {
+            let size = entry.data.len() as u64;
+            self.kept.push_back((key, size));
+            self.kept_bytes += size;
+        }
+        while self.kept_bytes > max_bytes {
+            let (evicted, size) = self.kept.pop_front().expect("kept bytes without kept data");
+            self.kept_bytes -= size;
+            let clean = self.cache.get(&evicted).map(|entry| entry.sync && !entry.is_open()).unwrap_or(false);
+            if clean {
+                info!("offline cache evicting {} from cache", evicted.0);
+                self.cache.remove(&evicted);
+            }
+        }
+    }
+
+    // Stops tracking kept data for `key` once the file is opened again or removed
+    fn claim_kept(&mut self, key: CacheKey) {
+        if let Some(pos) = self.kept.iter().position(|&(kept, _)| kept == key) {
+            let (_, size) = self.kept.remove(pos).unwrap();
+            self.kept_bytes -= size;
+        }
+    }
+
+    // Retries the failed write-backs that were last attempted over the retry interval ago,
+    // dropping the data from the cache once it is written back unless the file is open
+    fn retry_failed_writes(&mut self) {
+        let due: Vec<(CacheKey, Caller)> = self.failed_writes.iter()
+            .filter(|&(_, (_, failed))| failed.elapsed() >= WRITE_RETRY_INTERVAL)
+            .map(|(&key, (caller, _))| (key, caller.clone()))
+            .collect();
+
+        for (key, caller) in due {
+            match self.write_back(&caller, key) {
+                Ok(_) => {
+                    info!("retried write-back of {} succeeded", key.0);
+                    self.purge_closed(key);
+                }
+                Err(err) => warn!("retried write-back of {} failed - {}", key.0, err),
+            }
+        }
+    }
+
+    // Writes `data` to a new sibling of the file of `key` named after the current time,
+    // then discards the cached data of `key` so that the remote changes are read next.
+    // The entry is removed from the cache unless the file is open.
+    fn save_conflict_copy(&mut self, caller: &Caller, key: CacheKey, data: &[u8]) -> Result<(), LibcError> {
+        let ino = key.0;
+        let (path, attr) = {
+            let inode = &self.inodes[ino];
+            (inode.path.clone(), inode.attr)
+        };
+
+        let mut name = get_basename(&path).to_owned();
+        name.push(format!(".conflict-{}", time::now_utc().strftime("%Y%m%d%H%M%S").unwrap()));
+        let conflict_path = path.with_file_name(name);
+        let result = self.nfs.write(caller, &conflict_path, data, None);
+        self.record_call(&result);
+        let version = result?;
+        warn!("saved local changes to {} as {}", path.display(), conflict_path.display());
+
+        let now = time::now_utc().to_timespec();
+        let meta = Metadata {
+            size: data.len() as u64,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: attr.kind,
+            perm: attr.perm,
+            version: version,
+            id: None,
+            blocks: None,
+            owner: None,
+            group: None,
+        };
+        self.inodes.insert_metadata(&conflict_path, &meta);
+
+        let entry = self.cache.get_mut(&key).unwrap();
+        entry.discard();
+        if !entry.is_open() {
+            self.cache.remove(&key);
+        }
+        // Pick up the remote metadata of the original file (which may also be gone by now)
+        let _ = self.revalidate(caller, ino);
+        Ok(())
+    }
+
+    // Resizes the cached data for `key` to `size` bytes and marks it for write-back. Only the data
+    // that is kept is read from the backend, so truncating to empty never reads. Data that wasn't cached
+    // is written back conditional on `version`.
+    fn truncate_cache(&mut self, caller: &Caller, key: CacheKey, size: u64, version: Option<String>) -> Result<(), LibcError> {
+        let ino = key.0;
+        if self.inodes.get(ino).is_none() {
+            // removed while open
+            return Err(ENOENT);
+        }
+        self.claim_prefetched(ino);
+        self.cache.entry(key).or_insert_with(CacheEntry::new);
+        if size > 0 {
+            self.read_to_cache_if_needed(caller, key)?;
+        }
+
+        let entry = self.cache.get_mut(&key).unwrap();
+        if !entry.warm {
+            entry.version = version;
+        }
+        entry.data.resize(size as usize, 0);
+        entry.warm = true;
+        entry.sync = false;
+        self.inodes[ino].set_size(size);
+        Ok(())
+    }
+
+    // Writes back all data that hasn't been written yet, until the unmount timeout is over,
+    // then lets the backend clean up. Only runs once, whether it's called for FUSE_DESTROY
+    // or after the session ended.
+    fn unmount(&mut self) {
+        if self.unmounted {
+            return;
+        }
+        self.unmounted = true;
+        let deadline = Instant::now() + self.unmount_timeout;
+        let mut dirty: Vec<CacheKey> = self.cache.iter()
+            .filter(|&(_, entry)| entry.warm && !entry.sync)
+            .map(|(&key, _)| key)
+            .collect();
+        dirty.sort();
+
+        let mut lost = 0;
+        for key in dirty {
+            let path = match self.inodes.get(key.0) {
+                Some(inode) => inode.path.clone(),
+                None => continue,
+            };
+            if Instant::now() >= deadline {
+                error!("unmount timed out before writing back {}", path.display());
+                lost += 1;
+                continue;
+            }
+            let caller = self.writer(key);
+            if let Err(err) = self.write_back(&caller, key) {
+                error!("unmount failed to write back {} - {}", path.display(), err);
+                lost += 1;
+            }
+        }
+        if lost > 0 {
+            error!("unmounting with changes to {} files not written back", lost);
+        }
+        if let Some(ref journal) = self.journal {
+            if journal.len() > 0 {
+                info!("{} journaled changes are left to replay after the next mount", journal.len());
+            }
+        }
+
+        if let Err(err) = self.nfs.destroy() {
+            error!("backend destroy error - {}", err);
+        }
+        self.persist_inodes(true);
+    }
+
+    // Attributes of `ino`, revalidated first if it is versioned and expired
+    fn get_attr(&mut self, caller: &Caller, ino: u64) -> Result<FileAttr, LibcError> {
+        self.check_visible(caller, ino)?;
+        if self.is_expired(ino) {
+            self.revalidate(caller, ino)?;
+        }
+        match self.inodes.get(ino) {
+            Some(inode) => Ok(inode.attr),
+            None => {
+                info!("getattr ENOENT: {}", ino);
+                Err(ENOENT)
+            }
+        }
+    }
+
+    // Looks up `name` in the directory `parent` for `caller`, counting the reference the kernel
+    // takes on the inode it returns
+    fn lookup_child(&mut self, caller: &Caller, parent: u64, name: &Path) -> Result<u64, LibcError> {
+        if let Some(ino) = self.resolve_child(caller, parent, name)? {
+            return Ok(ino);
+        }
+        let child_path = self.inodes[parent].path.join(name);
+        let result = self.nfs.lookup(caller, &child_path);
+        self.looked_up(caller, &child_path, result)
+    }
+
+    // Resolves `name` in the directory `parent` like `lookup_child` does, but without looking it up
+    // in the backend. Returns None if only a lookup in the backend can tell.
+    fn resolve_child(&mut self, caller: &Caller, parent: u64, name: &Path) -> Result<Option<u64>, LibcError> {
+        // Clone until MIR NLL lands
+        if let Some(child_inode) = self.inodes.child(parent, &name).cloned() {
+            let ino = child_inode.attr.ino;
+            self.check_visible(caller, ino)?;
+            if self.is_expired(ino) {
+                self.revalidate(caller, ino)?;
+            }
+            self.inodes.add_lookup(ino);
+            return Ok(Some(ino));
+        }
+
+        // Clone until MIR NLL lands
+        let parent_inode = self.inodes[parent].clone();
+        let child_path = parent_inode.path.join(&name);
+        // The backend doesn't know about journaled changes yet
+        if let Some(op) = self.pending_op(&child_path) {
+            let ino = self.insert_journaled(&child_path, op).ok_or(ENOENT)?;
+            self.inodes.add_lookup(ino);
+            self.inodes_changed();
+            return Ok(Some(ino));
+        }
+        // Another user's listings and failed lookups don't tell what this user can see
+        if !self.isolate_users && (parent_inode.visited || self.inodes.is_negative(&child_path)) {
+            return Err(ENOENT);
+        }
+        if !self.backend_available() {
+            // pinned paths stay known while offline
+            let child_metadata = self.pins.as_ref().and_then(|pins| pins.metadata(&child_path)).ok_or(EIO)?;
+            let ino = self.inodes.insert_metadata(&child_path, &child_metadata).attr.ino;
+            self.inodes.add_lookup(ino);
+            self.inodes_changed();
+            return Ok(Some(ino));
+        }
+
+        // Answer from a listing of the parent, falling back to a lookup if listing fails
+        if self.lookup_strategy == LookupStrategy::Readdir {
+            match self.list_to_cache(caller, parent) {
+                Ok(listed) => {
+                    let result = match self.inodes.child(parent, &name).map(|child| child.attr.ino) {
+                        Some(ino) if listed.contains(&ino) => {
+                            self.inodes.add_lookup(ino);
+                            Ok(Some(ino))
+                        }
+                        _ => Err(ENOENT),
+                    };
+                    self.inodes_changed();
+                    return result;
+                }
+                Err(err) => {
+                    self.inodes_changed();
+                    info!("lookup listing {} failed - {}", parent_inode.path.display(), err);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Caches the result of looking up `path` in the backend, counting the reference the kernel
+    // takes on the inode it returns
+    fn looked_up(&mut self, caller: &Caller, path: &Path, result: Result<Metadata, LibcError>) -> Result<u64, LibcError> {
+        self.record_call(&result);
+        // the parent may have been removed while the lookup ran on a worker of `mount_async`
+        if path.parent().and_then(|parent| self.inodes.get_by_path(parent)).is_none() {
+            return Err(ENOENT);
+        }
+        match result {
+            Ok(metadata) => {
+                let ino = self.inodes.insert_metadata(path, &metadata).attr.ino;
+                self.mark_visible(caller, ino);
+                self.inodes.add_lookup(ino);
+                self.inodes_changed();
+                Ok(ino)
+            }
+            Err(err) => {
+                if let (ENOENT, Some(ttl), false) = (err, self.negative_ttl, self.isolate_users) {
+                    self.inodes.insert_negative(path, Instant::now() + ttl);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // Cached inodes may have been looked up by another user, so isolated users have the backend
+    // look up an inode it hasn't shown them before it is served from the cache. The root is always visible.
+    fn check_visible(&mut self, caller: &Caller, ino: u64) -> Result<(), LibcError> {
+        if !self.isolate_users || ino == 1 {
+            return Ok(());
+        }
+        let inode = self.inodes.get(ino).ok_or(ENOENT)?;
+        if inode.users.contains(&caller.uid) {
+            return Ok(());
+        }
+        let path = inode.path.clone();
+        if !self.backend_available() {
+            return Err(EIO);
+        }
+        let result = self.nfs.lookup(caller, &path);
+        self.record_call(&result);
+        result.map(|_| self.mark_visible(caller, ino)).inspect_err(|err| {
+            info!("{} is not visible to uid {} - {}", path.display(), caller.uid, err);
+        })
+    }
+
+    // Records that the backend showed `ino` to `caller`
+    fn mark_visible(&mut self, caller: &Caller, ino: u64) {
+        if let (true, Some(inode)) = (self.isolate_users, self.inodes.get_mut(ino)) {
+            inode.users.insert(caller.uid);
+        }
+    }
+
+    // Opens a handle to `ino` for `caller`, returning the handle number
+    fn open_file(&mut self, caller: &Caller, ino: u64, flags: u32) -> Result<u64, LibcError> {
+        self.retry_failed_writes();
+        self.collect_journal();
+
+        let path = self.inodes.get(ino).ok_or(ENOENT)?.path.clone();
+        let mut mode = match flags as c_int & O_ACCMODE {
+            O_RDONLY => R_OK,
+            O_WRONLY => W_OK,
+            O_RDWR => R_OK | W_OK,
+            _ => 0,
+        };
+        if flags as c_int & O_TRUNC != 0 {
+            mode |= W_OK;
+        }
+        self.check_access(caller, &path, mode)?;
+
+        self.collect_prefetched(None);
+
+        // Only keep cached data from earlier opens (or prefetching) if it is still current
+        let key = self.cache_key(caller, ino);
+        let cached = self.cache.get(&key).map(|e| e.warm && e.sync && !e.is_open()).unwrap_or(false);
+        if cached || self.is_expired(ino) {
+            self.revalidate(caller, ino)?;
+        }
+
+        self.claim_prefetched(ino);
+        self.claim_kept(key);
+        let mut handle = FileHandle {
+            caller: caller.clone(),
+            key: key,
+            flags: flags,
+            version: self.inodes[ino].version.clone(),
+            written: false,
+            error: None,
+        };
+
+        // The old contents are replaced, so there's no need to read them. The FUSE library doesn't
+        // negotiate atomic O_TRUNC, so Linux truncates with a separate setattr instead (see `truncate`).
+        if handle.is_writable() && flags as c_int & O_TRUNC != 0 {
+            self.truncate_cache(caller, key, 0, handle.version.clone())?;
+        }
+
+        let entry = self.cache.entry(key).or_insert_with(|| CacheEntry::new());
+        entry.opened();
+
+        // A writable handle also answers for changes not written back yet, like a file created by mknod
+        handle.written = handle.is_writable() && entry.warm && !entry.sync;
+        self.last_fh += 1;
+        self.file_handles.insert(self.last_fh, handle);
+
+        // Opening files of a directory one after another prefetches the siblings that follow
+        if let (Some(parent), Some(inode)) = (self.inodes.parent(ino), self.inodes.get(ino)) {
+            let opened = (parent.attr.ino, inode.cookie);
+            let sequential = match self.last_opened {
+                Some((last_parent, last_cookie)) => last_parent == opened.0 && last_cookie < opened.1,
+                None => false,
+            };
+            self.last_opened = Some(opened);
+            if sequential {
+                let window = self.prefetcher.as_ref().map(|p| p.options.sequential_window).unwrap_or(0);
+                self.prefetch_children(caller, opened.0, opened.1, window);
+            }
+        }
+
+        Ok(self.last_fh)
+    }
+
+    // Reads from the file through the handle `fh`, reading the whole file into the cache first if needed
+    fn read_file(&mut self, caller: &Caller, fh: u64, offset: u64, size: u32) -> Result<&[u8], LibcError> {
+        let key = match self.file_handles.get(&fh) {
+            Some(handle) if handle.is_readable() => handle.key,
+            _ => return Err(EBADF),
+        };
+
+        // Determine if we should hit the API
+        self.read_to_cache_if_needed(caller, key)?;
+
+        // Return the cached data
+        let buffer = &self.cache[&key].data;
+        let end_offset = offset + size as u64;
+        match buffer.len() {
+            len if len as u64 > offset + size as u64 => Ok(&buffer[(offset as usize)..(end_offset as usize)]),
+            len if len as u64 > offset => Ok(&buffer[(offset as usize)..]),
+            // reads at or past the end of the file, e.g. of a truncated file, get no data
+            _ => Ok(&[]),
+        }
+    }
+
+    // Writes to the cached data through the handle `fh`, returning the number of bytes written
+    fn write_file(&mut self, caller: &Caller, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<u32, LibcError> {
+        let (key, version, append) = match self.file_handles.get(&fh) {
+            Some(handle) if handle.is_writable() => (handle.key, handle.version.clone(), handle.flags as c_int & O_APPEND != 0),
+            _ => return Err(EBADF),
+        };
+
+        // a file removed while open can't be written to anymore
+        let size = self.inodes.get(ino).ok_or(ENOENT)?.attr.size;
+        let is_replace = !append && (offset == 0) && (size < data.len() as u64);
+
+        // Skip data lookup if write entirely replaces file or if we already cached the API response.
+        if !is_replace {
+            // Determine if we should hit the API
+            self.read_to_cache_if_needed(caller, key)?;
+        }
+
+        let new_size = match self.cache.get_mut(&key) {
+            Some(ref mut entry) => {
+                // data replacing the whole file still must not overwrite the remote changes
+                // made since this handle was opened unnoticed
+                if !entry.warm {
+                    entry.version = version;
+                }
+                // appends go to the end of the data, which may have grown since the kernel last saw the size
+                let offset = match append {
+                    true => entry.data.len() as u64,
+                    false => offset,
+                };
+                let end = data.len() + offset as usize;
+                if end > self.inodes[ino].attr.size as usize {
+                    entry.data.resize(end, 0);
+                }
+                entry.write(offset, data);
+                entry.data.len() as u64
+            }
+            None => {
+                error!("write failed to read file");
+                return Err(ENOENT);
+            }
+        };
+
+        self.inodes[ino].set_size(new_size);
+        self.file_handles.get_mut(&fh).unwrap().written = true;
+        Ok(data.len() as u32)
+    }
+
+    // Writes back the cached data if `fh` is writable, and returns the write-back error
+    // recorded for this handle, once
+    fn flush_file(&mut self, caller: &Caller, fh: u64) -> Result<(), LibcError> {
+        self.retry_failed_writes();
+        self.collect_journal();
+
+        let (key, writable) = match self.file_handles.get(&fh) {
+            Some(handle) => (handle.key, handle.is_writable()),
+            None => return Err(EBADF),
+        };
+        if writable {
+            // a failure is recorded on the handles that wrote the data
+            let _ = self.write_back(caller, key);
+        }
+
+        match self.file_handles.get_mut(&fh).and_then(|handle| handle.error.take()) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // Forgets the removed file or directory `ino`. Open files keep their cached data
+    // until they are released, but aren't written back anymore.
+    fn remove_inode(&mut self, ino: u64) {
+        for key in self.cache_keys(ino) {
+            if !self.cache[&key].is_open() {
+                self.cache.remove(&key);
+            }
+            self.failed_writes.remove(&key);
+            self.claim_kept(key);
+        }
+        self.claim_prefetched(ino);
+        self.inodes.remove(ino);
+    }
+
+    // Closes the handle `fh`. Once the file has no handles left, its data is written back if this
+    // handle could write, and dropped from the cache unless the write-back failed.
+    fn release_file(&mut self, caller: &Caller, fh: u64) -> Result<(), LibcError> {
+        let handle = match self.file_handles.remove(&fh) {
+            Some(handle) => handle,
+            None => return Err(EBADF),
+        };
+        let key = handle.key;
+        let handles = match self.cache.get_mut(&key) {
+            Some(entry) => entry.released(),
+            None => 0,
+        };
+
+        // Data is normally written back by flush already. Whatever is left after a failure stays
+        // in the cache to be retried, since release can't report errors.
+        // Read-only handles leave the data to the handles that wrote it
+        if handles == 0 && handle.is_writable() && !self.failed_writes.contains_key(&key) {
+            if let Err(err) = self.write_back(caller, key) {
+                error!("release flush error - {} (will retry)", err);
+            }
+        }
+
+        // Saving a conflict copy may have dropped the entry already.
+        // Data of a removed file has nowhere to go, and clean data may be kept to be read while offline
+        let removed = self.inodes.get(key.0).is_none();
+        match self.cache.get(&key) {
+            Some(entry) if handles == 0 && (!entry.warm || removed) => {
+                info!("release is purging {} from cache", key.0);
+                self.cache.remove(&key);
+            }
+            Some(entry) if handles == 0 && entry.sync => self.drop_clean(key),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Lists the entries of the directory `ino` after `offset` through the handle `fh`, passing each
+    // to `add` with its inode, cookie, type and name until `add` returns true for a full reply
+    fn read_dir(&mut self, caller: &Caller, ino: u64, fh: u64, offset: u64,
+                add: &mut dyn FnMut(u64, u64, FileType, &OsStr) -> bool) -> Result<(), LibcError> {
+        let parent_ino = match ino {
+            1 => 1,
+            _ => self.inodes.parent(ino).expect("inode has no parent").attr.ino,
+        };
+
+        if offset < 1 && add(ino, 1, FileType::Directory, OsStr::new(".")) {
+            return Ok(());
+        }
+        if offset < 2 && add(parent_ino, 2, FileType::Directory, OsStr::new("..")) {
+            return Ok(());
+        }
+
+        let mut last_cookie = offset;
+        let visible = self.dir_handles.get(&fh).and_then(|handle| handle.visible.as_ref());
+        for (name, child) in self.inodes.children_after(ino, offset) {
+            if visible.map(|visible| !visible.contains(&child.attr.ino)).unwrap_or(false) {
+                continue;
+            }
+            if add(child.attr.ino, child.cookie, child.attr.kind, name) {
+                return Ok(());
+            }
+            last_cookie = child.cookie;
+        }
+
+        if let Some(err) = self.dir_handles.get(&fh).and_then(|handle| handle.error) {
+            return Err(err);
+        }
+
+        // Take the listing out of the handle while pulling from it, then put it back unless exhausted.
+        // After an error the listing is dropped, so that later calls don't list past it
+        let (mut entries, mut listed) = match self.dir_handles.get_mut(&fh) {
+            Some(handle) if handle.entries.is_some() => (handle.entries.take().unwrap(), ::std::mem::take(&mut handle.listed)),
+            _ => return Ok(()),
+        };
+
+        let result = self.add_listing_entries(ino, &mut entries, &mut listed, last_cookie, add);
+        match result {
+            Ok(true) => {
+                // Mark this node visited once the network listing is exhausted,
+                // which also tells which aliases are gone
+                self.inodes.drop_unlisted_aliases(ino, &listed);
+                self.inodes.set_visited(ino, true);
+                self.prefetch_children(caller, ino, 0, usize::MAX);
+            }
+            Ok(false) => {
+                if let Some(handle) = self.dir_handles.get_mut(&fh) {
+                    handle.entries = Some(entries);
+                    handle.listed = listed;
+                }
+            }
+            Err(err) => {
+                if let Some(handle) = self.dir_handles.get_mut(&fh) {
+                    handle.error = Some(err);
+                }
+            }
+        }
+        // Enforce the inode cap once the listing is back with the handle or the directory is marked visited
+        self.inodes_changed();
+        result.map(|_| ())
+    }
+
+    // Adds the pinned children of the directory `ino` to the inode store.
+    // Returns false if the directory isn't pinned.
+    fn insert_pinned_children(&mut self, ino: u64) -> bool {
+        let path = self.inodes[ino].path.clone();
+        let children = match self.pins.as_ref().and_then(|pins| pins.children(&path)) {
+            Some(children) => children,
+            None => return false,
+        };
+        for child in children {
+            self.inodes.insert_metadata(&path.join(&child.filename), &child.metadata);
+        }
+        self.inodes_changed();
+        true
+    }
+
+    // Data of the pinned copy of `path`, if it has `version`, the current version of the file
+    fn pinned_data(&self, path: &Path, version: Option<&str>) -> Option<Vec<u8>> {
+        match (self.pins.as_ref(), version) {
+            (Some(pins), Some(version)) => pins.read(path, Some(version)),
+            _ => None,
+        }
+    }
+
+    // Reads the whole file into the cache, unless it is already there
+    fn read_to_cache_if_needed(&mut self, caller: &Caller, key: CacheKey) -> Result<(), LibcError> {
+        if let Some(path) = self.fill_cache_locally(key)? {
+            let mut buffer = Vec::new();
+            let result = self.nfs.read(caller, &path, &mut buffer).map(|_| buffer);
+            self.cache_read(key, result)?;
+        }
+        Ok(())
+    }
+
+    // Fills the cache for `key` unless it is already warm, from data that the journal has for the file
+    // and that isn't replayed yet, or from a pinned copy of the same version. Returns the path of the file
+    // if its data still has to be read from the backend.
+    fn fill_cache_locally(&mut self, key: CacheKey) -> Result<Option<PathBuf>, LibcError> {
+        let ino = key.0;
+        self.collect_prefetched(Some(ino));
+
+        // return if cache is already warm
+        if self.cache.get(&key).unwrap().warm {
+            return Ok(None);
+        }
+
+        let path = self.inodes.get(ino).ok_or(ENOENT)?.path.clone();
+        let data = match self.journal.as_ref().and_then(|journal| journal.pending_data(&path)) {
+            Some(data) => data.map_err(|err| {
+                error!("failed to read journaled data of {} - {}", path.display(), err);
+                EIO
+            })?,
+            None => match self.pinned_data(&path, self.inodes[ino].version.as_deref()) {
+                Some(data) => data,
+                // data that was never cached can't be read while offline, unless it is pinned
+                None if !self.backend_available() => self.pins.as_ref().and_then(|pins| pins.read(&path, None)).ok_or(EIO)?,
+                None => return Ok(Some(path)),
+            },
+        };
+        self.store_read(key, data);
+        Ok(None)
+    }
+
+    // Caches the result of reading the file for `key` from the backend
+    fn cache_read(&mut self, key: CacheKey, result: Result<Vec<u8>, LibcError>) -> Result<(), LibcError> {
+        self.record_call(&result);
+        self.store_read(key, result?);
+        Ok(())
+    }
+
+    // Stores the data read for `key`, unless the cache was filled or dropped while it was being read
+    fn store_read(&mut self, key: CacheKey, data: Vec<u8>) {
+        let version = self.inodes.get(key.0).and_then(|inode| inode.version.clone());
+        if let Some(entry) = self.cache.get_mut(&key).filter(|entry| !entry.warm) {
+            entry.set(data);
+            entry.sync = true;
+            entry.version = version;
+        }
+    }
+
+}
+
+fn get_basename(path: &Path) -> &OsStr {
+    path.file_name().expect("missing filename")
+}
+
+// Metadata of a file or directory created locally, which the backend doesn't know about yet
+fn local_metadata(kind: FileType, perm: u16, size: u64) -> Metadata {
+    let now = time::now_utc().to_timespec();
+    Metadata {
+        size: size,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind: kind,
+        perm: perm,
+        version: None,
+        id: None,
+        blocks: None,
+        owner: None,
+        group: None,
+    }
+}
+
+// The user and process that made `req`, with the command line of the process if `cmdline` is set
+fn request_caller(req: &Request, cmdline: bool) -> Caller {
+    let mut caller = Caller::new(req.uid(), req.gid(), req.pid());
+    if cmdline {
+        caller.read_cmdline();
+    }
+    caller
+}
+
+// Decides access by `caller` from the mode bits in `attr`
+fn check_mode(attr: &FileAttr, caller: &Caller, mode: c_int) -> Result<(), LibcError> {
+    let perm = attr.perm as c_int;
+    let granted = if caller.uid == 0 {
+        R_OK | W_OK | X_OK
+    } else if caller.uid == attr.uid {
+        perm >> 6
+    } else if caller.gid == attr.gid {
+        perm >> 3
+    } else {
+        perm
+    };
+    match mode & !granted & (R_OK | W_OK | X_OK) {
+        0 => Ok(()),
+        _ => Err(EACCES),
+    }
+}
+
+// Handlers of FUSE requests, which take the caller of the request in place of the request itself.
+// `Filesystem for NetFuse` serves them from the FUSE loop, and `AsyncNetFuse` from a thread of its own
+impl <NFS: NetworkFilesystem> NetFuse<NFS> {
+    // Release the kernel's references, and evict the inode once it has none
+    // unless it still has open or unwritten data in the cache
+    fn serve_forget(&mut self, ino: u64, nlookup: u64) {
+        if self.inodes.forget(ino, nlookup) == 0 && !self.is_cached(ino) && !self.is_journaled(ino) && self.inodes.evict(ino) {
+            self.inodes_changed();
+        }
+    }
+
+    // Return the cached inode, revalidating it first if it is versioned and expired
+    fn serve_getattr(&mut self, caller: &Caller, ino: u64, reply: ReplyAttr) {
+        match self.get_attr(caller, ino) {
+            Ok(attr) => reply.attr(&DEFAULT_TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    // Unless the directory was already listed, start the network listing now
+    // and keep it with the handle so that `readdir` can pull entries as they are needed
+    fn serve_opendir(&mut self, caller: &Caller, ino: u64, flags: u32, reply: ReplyOpen) {
+        // Isolated users each list the whole directory with their own credentials,
+        // and only see the children in their listing
+        let visited = match self.inodes.get(ino) {
+            Some(inode) => inode.visited,
+            None => return reply.error(ENOENT),
+        };
+        let handle = if self.isolate_users {
+            let listed = self.list_to_cache(caller, ino);
+            self.inodes_changed();
+            match listed {
+                Ok(listed) => DirHandle::new(ino, None, Some(listed)),
+                Err(err) => return reply.error(err),
+            }
+        } else if visited {
+            DirHandle::new(ino, None, None)
+        } else if self.backend_available() {
+            let path = self.inodes[ino].path.clone();
+            self.insert_journaled_children(ino);
+            DirHandle::new(ino, Some(self.nfs.readdir(caller, &path)), None)
+        } else if self.insert_pinned_children(ino) || !self.inodes.children(ino).is_empty() {
+            // While offline, serve the children that are pinned or known
+            DirHandle::new(ino, None, None)
+        } else {
+            return reply.error(EIO);
+        };
+
+        self.last_fh += 1;
+        self.dir_handles.insert(self.last_fh, handle);
+        reply.opened(self.last_fh, flags);
+    }
+
+    // Each entry is added with its directory cookie as the offset, and the kernel passes back
+    // the cookie of the last entry it received, so listing resumes with the entries after it.
+    // Cookies 1 and 2 are reserved for "." and ".."
+    //
+    // Entries already in the inode store are listed first, then entries are pulled from the
+    // handle's network listing until the reply is full. New entries always get a larger cookie
+    // than anything already listed, so the order stays stable across calls.
+    fn serve_readdir(&mut self, caller: &Caller, ino: u64, fh: u64, offset: u64, mut reply: ReplyDirectory) {
+        let result = self.read_dir(caller, ino, fh, offset, &mut |ino, cookie, kind, name| reply.add(ino, cookie, kind, name));
+        match result {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn serve_access(&mut self, caller: &Caller, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let path = match self.inodes.get(ino) {
+            Some(inode) => inode.path.clone(),
+            None => return reply.error(ENOENT),
+        };
+        match self.check_access(caller, &path, mask as c_int) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    // The pin attribute of a pinned path holds its sync status. The FUSE library doesn't pass the
+    // buffer size, so every value is 8 bytes long: asking for the size gets those 8 bytes as
+    // its answer, whose first 4 bytes the kernel caps at the largest attribute size.
+    fn serve_getxattr(&mut self, caller: &Caller, ino: u64, name: &OsStr, reply: ReplyData) {
+        if self.pins.is_none() {
+            return reply.error(ENOSYS);
+        }
+        if name != PIN_XATTR {
+            return reply.error(ENODATA);
+        }
+        if let Err(err) = self.check_visible(caller, ino) {
+            return reply.error(err);
+        }
+        let status = match self.inodes.get(ino) {
+            Some(inode) => self.pins.as_ref().unwrap().status(&inode.path),
+            None => return reply.error(ENOENT),
+        };
+        let value = match status {
+            Some(PinStatus::Pending) => "pending",
+            Some(PinStatus::Syncing { .. }) => "syncing",
+            Some(PinStatus::Synced { .. }) => "synced",
+            Some(PinStatus::Failed(_)) => "failed",
+            None => return reply.error(ENODATA),
+        };
+        reply.data(format!("{:8}", value).as_bytes());
+    }
+
+    // Setting the pin attribute pins the path for the caller, provided they can read it
+    fn serve_setxattr(&mut self, caller: &Caller, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        if name != PIN_XATTR || self.pins.is_none() {
+            return reply.error(ENOTSUP);
+        }
+        let path = match self.inodes.get(ino) {
+            Some(inode) => inode.path.clone(),
+            None => return reply.error(ENOENT),
+        };
+        if let Err(err) = self.check_access(caller, &path, R_OK) {
+            return reply.error(err);
+        }
+        self.pins.as_ref().unwrap().pin_for(&path, caller);
+        reply.ok();
+    }
+
+    // Removing the pin attribute unpins the path, with the same access required as for pinning it
+    fn serve_removexattr(&mut self, caller: &Caller, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        if name != PIN_XATTR || self.pins.is_none() {
+            return reply.error(ENOTSUP);
+        }
+        let path = match self.inodes.get(ino) {
+            Some(inode) => inode.path.clone(),
+            None => return reply.error(ENOENT),
+        };
+        if let Err(err) = self.check_access(caller, &path, R_OK) {
+            return reply.error(err);
+        }
+        match self.pins.as_ref().unwrap().unpin(&path) {
+            true => reply.ok(),
+            false => reply.error(ENODATA),
+        }
+    }
+
+    fn serve_releasedir(&mut self, fh: u64, reply: ReplyEmpty) {
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn serve_mknod(&mut self, caller: &Caller, parent: u64, name: &Path, mode: u32, reply: ReplyEntry) {
+        let parent_path = self.inodes[parent].path.clone();
+        if let Err(err) = self.check_access(caller, &parent_path, W_OK | X_OK) {
+            return reply.error(err);
+        }
+
+        let path = parent_path.join(&name);
+        let now = time::now_utc().to_timespec();
+
+        let meta = Metadata {
+            size: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::RegularFile,
+            perm: mode as u16,  // TODO: should this be based on _mode or parent -x bits (e.g. & 0o666)
+            version: None,
+            id: None,
+            blocks: None,
+            owner: None,
+            group: None,
+        };
+
+        // FIXME: cloning because it's quick-and-dirty
+        let attr = self.inodes.insert_metadata(&Path::new(&path), &meta).attr.clone();
+        self.mark_visible(caller, attr.ino);
+
+        // Need to add an entry and declare it warm, so that empty files can be created on release/fsync
+        //   but don't increment opened handles until `open` is called
+        let key = self.cache_key(caller, attr.ino);
+        let entry = self.cache.entry(key).or_insert_with(|| CacheEntry::new());
+        entry.warm = true;
+        self.inodes_changed();
+
+        self.reply_entry(attr.ino, reply);
+    }
+
+    fn serve_mkdir(&mut self, caller: &Caller, parent: u64, name: &Path, mode: u32, reply: ReplyEntry) {
+        let parent_path = self.inodes[parent].path.clone();
+        if let Err(err) = self.check_access(caller, &parent_path, W_OK | X_OK) {
+            return reply.error(err);
+        }
+
+        let path = parent_path.join(&name);
+        match self.apply_change(JournalOp::Mkdir, caller, &path) {
+            Ok(_) => {
+                let now = time::now_utc().to_timespec();
+                let meta = Metadata {
+                    size: 0,
+                    atime: now,
+                    mtime: now,
+                    ctime: now,
+                    crtime: now,
+                    kind: FileType::Directory,
+                    perm: mode as u16,  // TODO: should this be based on _mode or parent
+                    version: None,
+                    id: None,
+                    blocks: None,
+                    owner: None,
+                    group: None,
+                };
+
+                let attr = self.inodes.insert_metadata(&path, &meta).attr;
+                self.mark_visible(caller, attr.ino);
+                // a journaled directory can't be listed from the backend before it's replayed, but it's empty
+                if self.journal.is_some() {
+                    self.inodes.set_visited(attr.ino, true);
+                }
+
+                self.reply_entry(attr.ino, reply);
+                self.inodes_changed();
+            }
+            Err(err) => {
+                error!("mkdir error - {}", err);
+                reply.error(err);
+            }
+        }
+    }
+
+    fn serve_open(&mut self, caller: &Caller, ino: u64, flags: u32, reply: ReplyOpen) {
+        match self.open_file(caller, ino, flags) {
+            // The reply takes FOPEN_* flags rather than the open flags. The kernel page cache is
+            // shared by every user, so isolated users bypass it to only see their own data
+            Ok(fh) if self.isolate_users => reply.opened(fh, FOPEN_DIRECT_IO),
+            Ok(fh) => reply.opened(fh, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    // Called for every close of a handle
+    fn serve_flush(&mut self, caller: &Caller, ino: u64, fh: u64, reply: ReplyEmpty) {
+        match self.flush_file(caller, fh) {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                error!("flush(ino={}) error - {}", ino, err);
+                reply.error(err)
+            }
+        }
+    }
+
+    fn serve_release(&mut self, caller: &Caller, fh: u64, reply: ReplyEmpty) {
+        match self.release_file(caller, fh) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn serve_fsync(&mut self, caller: &Caller, fh: u64, reply: ReplyEmpty) {
+        let key = match self.file_handles.get(&fh) {
+            Some(handle) => handle.key,
+            None => return reply.error(EBADF),
+        };
+        let result = self.write_back(caller, key);
+        // the error is reported here rather than by the next flush
+        if let Some(handle) = self.file_handles.get_mut(&fh) {
+            handle.error = None;
+        }
+
+        match result {
+            Ok(_) => reply.ok(),
+            Err(err) => {
+                error!("fsync error - {}", err);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn serve_write(&mut self, caller: &Caller, ino: u64, fh: u64, offset: u64, data: &[u8], reply: ReplyWrite) {
+        // TODO: check if in read-only mode: EROFS
+        match self.write_file(caller, ino, fh, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    // Applies the size and owner changes of a setattr request, returning the updated attributes
+    fn set_attr(&mut self, caller: &Caller, ino: u64, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, fh: Option<u64>) -> Result<FileAttr, LibcError> {
+        if size.is_some() {
+            let path = self.inodes.get(ino).ok_or(ENOENT)?.path.clone();
+            self.check_access(caller, &path, W_OK)?;
+        }
+        if self.inodes.id_map().is_some() && (uid.is_some() || gid.is_some()) {
+            if let Err(err) = self.chown(caller, ino, uid, gid) {
+                warn!("chown(ino={}) failed - {}", ino, err);
+                return Err(err);
+            }
+        }
+
+        if let Some(new_size) = size {
+            if let Err(err) = self.truncate(caller, ino, fh, new_size) {
+                warn!("truncate(ino={}) failed - {}", ino, err);
+                return Err(err);
+            }
+        }
+
+        let inode = self.inodes.get_mut(ino).ok_or(ENOENT)?;
+        if let Some(new_uid) = uid {
+            inode.attr.uid = new_uid;
+        }
+        if let Some(new_gid) = gid {
+            inode.attr.gid = new_gid;
+        }
+        // TODO: is mode (u32) equivalent to attr.perm (u16)?
+        Ok(inode.attr)
+    }
 
+    fn serve_rmdir(&mut self, caller: &Caller, parent: u64, name: &Path, reply: ReplyEmpty) {
         let ino_opt = self.inodes.child(parent, &name).map(|inode| inode.attr.ino);
-        let path = self.inodes[parent].path.join(name);
-        match self.nfs.rmdir(&Path::new(&path)) {
+        let parent_path = self.inodes[parent].path.clone();
+        if let Err(err) = self.check_access(caller, &parent_path, W_OK | X_OK) {
+            return reply.error(err);
+        }
+        let path = parent_path.join(name);
+        if let Err(err) = self.check_journaled_rmdir(ino_opt, &path) {
+            return reply.error(err);
+        }
+        match self.apply_change(JournalOp::Rmdir, caller, &path) {
             Ok(_) => {
-                ino_opt.map(|ino| {
-                    self.inodes.remove(ino);
-                    self.cache.remove(&ino);
-                });
+                if let Some(ino) = ino_opt {
+                    self.remove_inode(ino);
+                }
+                self.inodes_changed();
                 reply.ok()
             },
             Err(err) => {
@@ -436,17 +2235,19 @@ impl <NFS: NetworkFilesystem> Filesystem for NetFuse<NFS> {
         }
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEmpty) {
-        debug!("unlink(parent={}, name={})", parent, name.display());
-
+    fn serve_unlink(&mut self, caller: &Caller, parent: u64, name: &Path, reply: ReplyEmpty) {
         let ino_opt = self.inodes.child(parent, &name).map(|inode| inode.attr.ino);
-        let path = self.inodes[parent].path.join(name);
-        match self.nfs.unlink(&Path::new(&path)) {
+        let parent_path = self.inodes[parent].path.clone();
+        if let Err(err) = self.check_access(caller, &parent_path, W_OK | X_OK) {
+            return reply.error(err);
+        }
+        let path = parent_path.join(name);
+        match self.apply_change(JournalOp::Unlink, caller, &path) {
             Ok(_) => {
-                ino_opt.map(|ino| {
-                    self.inodes.remove(ino);
-                    self.cache.remove(&ino);
-                });
+                if let Some(ino) = ino_opt {
+                    self.remove_inode(ino);
+                }
+                self.inodes_changed();
                 reply.ok()
             },
             Err(err) => {
@@ -455,6 +2256,807 @@ impl <NFS: NetworkFilesystem> Filesystem for NetFuse<NFS> {
             }
         }
     }
+}
+
+impl <NFS: NetworkFilesystem> Filesystem for NetFuse<NFS> {
+
+    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+        self.nfs.init()
+    }
+
+    fn destroy(&mut self, _req: &Request) {
+        self.unmount();
+    }
+
+    // If parent is marked visited, then only perform lookup in the cache
+    // otherwise, if the cache lookup is a miss, perform the network lookup
+    // (or list the parent, depending on the lookup strategy)
+    // unless the name recently failed a lookup
+    fn lookup(&mut self, req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
+        debug!("lookup(parent={}, name=\"{}\")", parent, name.display());
+        let caller = self.caller(req);
+        match self.lookup_child(&caller, parent, name) {
+            Ok(ino) => {
+                let inode = &self.inodes[ino];
+                reply.entry(&DEFAULT_TTL, &inode.attr, inode.generation);
+            }
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        debug!("forget(ino={}, nlookup={})", ino, nlookup);
+        self.serve_forget(ino, nlookup);
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        let caller = self.caller(req);
+        self.serve_getattr(&caller, ino, reply);
+    }
+
+    // If the data cache for this ino not warm, call the network read to populated the cache
+    // then use the offset and size to return the right part of the cached data
+    fn read(&mut self, req: &Request, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+        debug!("read(ino={}, fh={}, offset={}, size={})", ino, fh, offset, size);
+        let caller = self.caller(req);
+        match self.read_file(&caller, fh, offset, size) {
+            Ok(data) => reply.data(data),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        debug!("opendir(ino={}, flags=0x{:x})", ino, flags);
+        let caller = self.caller(req);
+        self.serve_opendir(&caller, ino, flags, reply);
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: u64, reply: ReplyDirectory) {
+        debug!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
+        let caller = self.caller(req);
+        self.serve_readdir(&caller, ino, fh, offset, reply);
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        debug!("access(ino={}, mask={})", ino, mask);
+        let caller = self.caller(req);
+        self.serve_access(&caller, ino, mask, reply);
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyData) {
+        debug!("getxattr(ino={}, name={:?})", ino, name);
+        let caller = self.caller(req);
+        self.serve_getxattr(&caller, ino, name, reply);
+    }
+
+    fn setxattr(&mut self, req: &Request, ino: u64, name: &OsStr, _value: &[u8], flags: u32, _position: u32, reply: ReplyEmpty) {
+        debug!("setxattr(ino={}, name={:?}, flags=0x{:x})", ino, name, flags);
+        let caller = self.caller(req);
+        self.serve_setxattr(&caller, ino, name, reply);
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr(ino={}, name={:?})", ino, name);
+        let caller = self.caller(req);
+        self.serve_removexattr(&caller, ino, name, reply);
+    }
+
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        debug!("releasedir(ino={}, fh={}, flags=0x{:x})", ino, fh, flags);
+        self.serve_releasedir(fh, reply);
+    }
+
+    fn mknod(&mut self, req: &Request, parent: u64, name: &Path, mode: u32, _rdev: u32, reply: ReplyEntry) {
+        debug!("mknod(parent={}, name={}, mode=0o{:o})", parent, name.display(), mode);
+        let caller = self.caller(req);
+        self.serve_mknod(&caller, parent, name, mode, reply);
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &Path, mode: u32, reply: ReplyEntry) {
+        debug!("mkdir(parent={}, name={}, mode=0o{:o})", parent, name.display(), mode);
+        let caller = self.caller(req);
+        self.serve_mkdir(&caller, parent, name, mode, reply);
+    }
+
+    fn open (&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        debug!("open(ino={}, flags=0x{:x})", ino, flags);
+        let caller = self.caller(req);
+        self.serve_open(&caller, ino, flags, reply);
+    }
+
+    fn flush(&mut self, req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        debug!("flush(ino={}, fh={})", ino, fh);
+        let caller = self.caller(req);
+        self.serve_flush(&caller, ino, fh, reply);
+    }
+
+    fn release (&mut self, req: &Request, ino: u64, fh: u64, flags: u32, _lock_owner: u64, flush: bool, reply: ReplyEmpty) {
+        debug!("release(ino={}, fh={}, flags=0x{:x}, flush={})", ino, fh, flags, flush);
+        let caller = self.caller(req);
+        self.serve_release(&caller, fh, reply);
+    }
+
+    fn fsync (&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        debug!("fsync(ino={}, fh={}, datasync={})", ino, fh, datasync);
+        let caller = self.caller(req);
+        self.serve_fsync(&caller, fh, reply);
+    }
+
+    fn write (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, data: &[u8], flags: u32, reply: ReplyWrite) {
+        debug!("write(ino={}, fh={}, offset={}, len={}, flags=0x{:x})", ino, fh, offset, data.len(), flags);
+        let caller = self.caller(req);
+        self.serve_write(&caller, ino, fh, offset, data, reply);
+    }
+
+    fn setattr (&mut self, req: &Request, ino: u64, _mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, flags:               Option<u32>, reply: ReplyAttr) {
+        debug!("setattr(ino={}, mode={:?}, size={:?}, fh={:?}, flags={:?})", ino, _mode, size, fh, flags);
+        let caller = self.caller(req);
+        match self.set_attr(&caller, ino, uid, gid, size, fh) {
+            Ok(attr) => reply.attr(&DEFAULT_TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &Path, reply: ReplyEmpty) {
+        debug!("rmdir(parent={}, name={})", parent, name.display());
+        let caller = self.caller(req);
+        self.serve_rmdir(&caller, parent, name, reply);
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &Path, reply: ReplyEmpty) {
+        debug!("unlink(parent={}, name={})", parent, name.display());
+        let caller = self.caller(req);
+        self.serve_unlink(&caller, parent, name, reply);
+    }
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::path::PathBuf;
+    use std::process;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use libc::{ECONNREFUSED, ETIMEDOUT};
+
+    // Backend keeping files in memory, with a version that is bumped by every write
+    #[derive(Clone, Default)]
+    struct MemoryFs {
+        state: Arc<Mutex<MemoryState>>,
+    }
+
+    #[derive(Default)]
+    struct MemoryState {
+        files: HashMap<PathBuf, (Vec<u8>, u64)>,
+        // error returned by every write and unlink, if set
+        write_error: Option<LibcError>,
+        // error returned by every access check, if set
+        access_error: Option<LibcError>,
+        // paths and modes of the access checks so far
+        access_checks: Vec<(PathBuf, u32)>,
+        // uid that lookups and listings hide every file from, if set
+        hidden_from: Option<u32>,
+        // uids of the callers of each read and write
+        callers: Vec<u32>,
+        writes: usize,
+        lookups: usize,
+        listings: usize,
+    }
+
+    impl MemoryFs {
+        fn with_file(path: &str, data: &[u8]) -> MemoryFs {
+            let fs = MemoryFs::default();
+            fs.state.lock().unwrap().files.insert(PathBuf::from(path), (data.to_vec(), 1));
+            fs
+        }
+
+        fn data(&self, path: &str) -> Option<Vec<u8>> {
+            self.state.lock().unwrap().files.get(Path::new(path)).map(|(data, _)| data.clone())
+        }
+
+        fn metadata(&self, path: &Path) -> Option<Metadata> {
+            let state = self.state.lock().unwrap();
+            state.files.get(path).map(|&(ref data, version)| {
+                let now = time::now_utc().to_timespec();
+                Metadata {
+                    size: data.len() as u64,
+                    atime: now,
+                    mtime: now,
+                    ctime: now,
+                    crtime: now,
+                    kind: FileType::RegularFile,
+                    perm: 0o644,
+                    version: Some(version.to_string()),
+                    id: None,
+                    blocks: None,
+                    owner: None,
+                    group: None,
+                }
+            })
+        }
+    }
+
+    impl NetworkFilesystem for MemoryFs {
+        fn lookup(&mut self, caller: &Caller, path: &Path) -> Result<Metadata, LibcError> {
+            {
+                let mut state = self.state.lock().unwrap();
+                state.lookups += 1;
+                if state.hidden_from == Some(caller.uid) {
+                    return Err(ENOENT);
+                }
+            }
+            self.metadata(path).ok_or(ENOENT)
+        }
+
+        fn readdir(&mut self, caller: &Caller, path: &Path) -> DirIterator {
+            let names: Vec<PathBuf> = {
+                let mut state = self.state.lock().unwrap();
+                state.listings += 1;
+                match state.hidden_from == Some(caller.uid) {
+                    true => Vec::new(),
+                    false => state.files.keys().filter(|file| file.parent() == Some(path)).cloned().collect(),
+                }
+            };
+            let entries: Vec<Result<DirEntry, LibcError>> = names.iter()
+                .map(|file| Ok(DirEntry::new(file.file_name().unwrap(), self.metadata(file).unwrap())))
+                .collect();
+            Box::new(entries.into_iter())
+        }
+
+        fn read(&mut self, caller: &Caller, path: &Path, buffer: &mut Vec<u8>) -> Result<usize, LibcError> {
+            let mut state = self.state.lock().unwrap();
+            state.callers.push(caller.uid);
+            let data = state.files.get(path).map(|(data, _)| data.clone()).ok_or(ENOENT)?;
+            buffer.extend_from_slice(&data);
+            Ok(data.len())
+        }
+
+        fn write(&mut self, caller: &Caller, path: &Path, data: &[u8], version: Option<&str>) -> Result<Option<String>, LibcError> {
+            let mut state = self.state.lock().unwrap();
+            state.callers.push(caller.uid);
+            if let Some(err) = state.write_error {
+                return Err(err);
+            }
+            let current = state.files.get(path).map(|&(_, current)| current).unwrap_or(0);
+            if version.map(|version| version != current.to_string()).unwrap_or(false) {
+                return Err(ESTALE);
+            }
+            state.writes += 1;
+            state.files.insert(path.to_owned(), (data.to_vec(), current + 1));
+            Ok(Some((current + 1).to_string()))
+        }
+
+        fn unlink(&mut self, _caller: &Caller, path: &Path) -> Result<(), LibcError> {
+            let mut state = self.state.lock().unwrap();
+            if let Some(err) = state.write_error {
+                return Err(err);
+            }
+            state.files.remove(path).map(|_| ()).ok_or(ENOENT)
+        }
+
+        fn clone_for_journal(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+            Some(Box::new(self.clone()))
+        }
+
+        fn check_access(&mut self, _caller: &Caller, path: &Path, mode: u32) -> Result<(), LibcError> {
+            let mut state = self.state.lock().unwrap();
+            state.access_checks.push((path.to_owned(), mode));
+            state.access_error.map(Err).unwrap_or(Ok(()))
+        }
+    }
+
+    fn caller() -> Caller {
+        Caller::new(1000, 1000, 42)
+    }
+
+    fn netfuse(fs: &MemoryFs, options: MountOptions) -> NetFuse<MemoryFs> {
+        NetFuse::new(fs.clone(), &options)
+    }
+
+    // Inode of the backend file at `path`, as if it was looked up
+    fn lookup(netfuse: &mut NetFuse<MemoryFs>, path: &str) -> u64 {
+        let metadata = netfuse.nfs.metadata(Path::new(path)).expect("no such file");
+        netfuse.inodes.insert_metadata(path, &metadata).attr.ino
+    }
+
+    #[test]
+    fn test_lookup_strategies() {
+        let fs = MemoryFs::with_file("/a.txt", b"a");
+        fs.state.lock().unwrap().files.insert(PathBuf::from("/b.txt"), (b"b".to_vec(), 1));
+
+        // misses are looked up one by one by default
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        assert!(netfuse.lookup_child(&caller(), 1, Path::new("a.txt")).is_ok());
+        assert!(netfuse.lookup_child(&caller(), 1, Path::new("b.txt")).is_ok());
+        assert_eq!(fs.state.lock().unwrap().lookups, 2);
+        assert_eq!(fs.state.lock().unwrap().listings, 0);
+
+        // or answered from a single listing of the parent
+        let options = MountOptions::new(&"/mnt").lookup_strategy(LookupStrategy::Readdir);
+        let mut netfuse = self::netfuse(&fs, options);
+        let ino = netfuse.lookup_child(&caller(), 1, Path::new("a.txt")).unwrap();
+        assert_eq!(netfuse.inodes[ino].path, Path::new("/a.txt"));
+        assert!(netfuse.lookup_child(&caller(), 1, Path::new("b.txt")).is_ok());
+        assert_eq!(netfuse.lookup_child(&caller(), 1, Path::new("c.txt")), Err(ENOENT));
+        assert_eq!(fs.state.lock().unwrap().lookups, 2);
+        assert_eq!(fs.state.lock().unwrap().listings, 1);
+        assert!(netfuse.inodes[1].visited);
+    }
+
+    #[test]
+    fn test_unmount_writes_back_dirty_data() {
+        let fs = MemoryFs::with_file("/a.txt", b"old");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = lookup(&mut netfuse, "/a.txt");
+        netfuse.cache.entry((ino, None)).or_insert_with(CacheEntry::new).set(&b"new"[..]);
+
+        netfuse.unmount();
+        assert_eq!(fs.data("/a.txt"), Some(b"new".to_vec()));
+        assert!(netfuse.cache[&(ino, None)].sync);
+
+        // unmounting again after FUSE_DESTROY does nothing
+        netfuse.cache.get_mut(&(ino, None)).unwrap().set(&b"newer"[..]);
+        netfuse.unmount();
+        assert_eq!(fs.data("/a.txt"), Some(b"new".to_vec()));
+
+        // nothing is written back once the unmount timeout is over
+        let mut netfuse = self::netfuse(&fs, MountOptions::new(&"/mnt").unmount_timeout(Duration::from_secs(0)));
+        let ino = lookup(&mut netfuse, "/a.txt");
+        netfuse.cache.entry((ino, None)).or_insert_with(CacheEntry::new).set(&b"lost"[..]);
+        netfuse.unmount();
+        assert_eq!(fs.data("/a.txt"), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_access_modes() {
+        let fs = MemoryFs::with_file("/a.txt", b"abc");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = lookup(&mut netfuse, "/a.txt");
+
+        let reader = netfuse.open_file(&caller(), ino, O_RDONLY as u32).unwrap();
+        assert_eq!(netfuse.write_file(&caller(), ino, reader, 0, b"x"), Err(EBADF));
+        assert_eq!(netfuse.read_file(&caller(), reader, 0, 10), Ok(&b"abc"[..]));
+
+        let writer = netfuse.open_file(&caller(), ino, O_WRONLY as u32).unwrap();
+        assert_eq!(netfuse.read_file(&caller(), writer, 0, 10), Err(EBADF));
+        assert_eq!(netfuse.write_file(&caller(), ino, writer, 1, b"x"), Ok(1));
+        assert_eq!(netfuse.read_file(&caller(), 999, 0, 10), Err(EBADF));
+        assert_eq!(netfuse.flush_file(&caller(), 999), Err(EBADF));
+    }
+
+    #[test]
+    fn test_access_checks() {
+        let fs = MemoryFs::with_file("/a.txt", b"abc");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = lookup(&mut netfuse, "/a.txt");
+
+        // the backend decides with the access mode of the open flags, and truncating needs write access
+        fs.state.lock().unwrap().access_error = Some(EACCES);
+        assert_eq!(netfuse.open_file(&caller(), ino, O_RDWR as u32), Err(EACCES));
+        assert_eq!(netfuse.open_file(&caller(), ino, (O_RDONLY | O_TRUNC) as u32), Err(EACCES));
+        let checks = fs.state.lock().unwrap().access_checks.clone();
+        let mode = (R_OK | W_OK) as u32;
+        assert_eq!(checks, vec![(PathBuf::from("/a.txt"), mode), (PathBuf::from("/a.txt"), mode)]);
+
+        // with default_permissions the kernel checks instead
+        fs.state.lock().unwrap().access_checks.clear();
+        let mut netfuse = self::netfuse(&fs, MountOptions::new(&"/mnt").default_permissions(true));
+        let ino = lookup(&mut netfuse, "/a.txt");
+        assert!(netfuse.open_file(&caller(), ino, O_RDWR as u32).is_ok());
+        assert!(fs.state.lock().unwrap().access_checks.is_empty());
+    }
+
+    #[test]
+    fn test_caller_reaches_backend() {
+        let fs = MemoryFs::with_file("/a.txt", b"abc");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = lookup(&mut netfuse, "/a.txt");
+
+        // data is read as the user who opened the file, and written back as the one who wrote it
+        let reader = netfuse.open_file(&caller(), ino, O_RDONLY as u32).unwrap();
+        assert_eq!(netfuse.read_file(&caller(), reader, 0, 10), Ok(&b"abc"[..]));
+        let other = Caller::new(1001, 1001, 43);
+        let writer = netfuse.open_file(&other, ino, O_WRONLY as u32).unwrap();
+        assert_eq!(netfuse.write_file(&other, ino, writer, 0, b"x"), Ok(1));
+        assert_eq!(netfuse.flush_file(&other, writer), Ok(()));
+        assert_eq!(fs.state.lock().unwrap().callers, vec![1000, 1001]);
+
+        // the command line is read from /proc for running processes
+        let mut caller = Caller::new(1000, 1000, process::id());
+        caller.read_cmdline();
+        assert!(caller.cmdline.map(|cmdline| !cmdline.is_empty()).unwrap_or(false));
+        let mut caller = Caller::new(1000, 1000, u32::MAX);
+        caller.read_cmdline();
+        assert_eq!(caller.cmdline, None);
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        let fs = MemoryFs::with_file("/a.txt", b"abc");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = lookup(&mut netfuse, "/a.txt");
+
+        let fh = netfuse.open_file(&caller(), ino, (O_RDWR | O_TRUNC) as u32).unwrap();
+        assert_eq!(netfuse.read_file(&caller(), fh, 0, 10), Ok(&b""[..]));
+        assert_eq!(netfuse.write_file(&caller(), ino, fh, 0, b"x"), Ok(1));
+        assert_eq!(netfuse.read_file(&caller(), fh, 1, 10), Ok(&b""[..]));
+        assert_eq!(netfuse.read_file(&caller(), fh, 5, 10), Ok(&b""[..]));
+    }
+
+    #[test]
+    fn test_append() {
+        let fs = MemoryFs::with_file("/log.txt", b"abc");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = lookup(&mut netfuse, "/log.txt");
+
+        // appends go to the end whatever offset the kernel passes
+        let fh = netfuse.open_file(&caller(), ino, (O_WRONLY | O_APPEND) as u32).unwrap();
+        assert_eq!(netfuse.write_file(&caller(), ino, fh, 0, b"de"), Ok(2));
+        assert_eq!(netfuse.write_file(&caller(), ino, fh, 0, b"f"), Ok(1));
+        assert_eq!(netfuse.flush_file(&caller(), fh), Ok(()));
+        assert_eq!(fs.data("/log.txt"), Some(b"abcdef".to_vec()));
+        assert_eq!(netfuse.inodes[ino].attr.size, 6);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let fs = MemoryFs::with_file("/a.txt", b"old contents");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = lookup(&mut netfuse, "/a.txt");
+
+        // opening with O_TRUNC truncates with a setattr without handle, which waits for the new data
+        let fh = netfuse.open_file(&caller(), ino, O_WRONLY as u32).unwrap();
+        assert_eq!(netfuse.truncate(&caller(), ino, None, 0), Ok(()));
+        assert_eq!(fs.data("/a.txt"), Some(b"old contents".to_vec()));
+        assert_eq!(netfuse.write_file(&caller(), ino, fh, 0, b"new"), Ok(3));
+        assert_eq!(netfuse.flush_file(&caller(), fh), Ok(()));
+        assert_eq!(fs.data("/a.txt"), Some(b"new".to_vec()));
+        assert_eq!(fs.state.lock().unwrap().writes, 1);
+
+        // ftruncate goes through the handle too, and keeps the start of the file
+        assert_eq!(netfuse.truncate(&caller(), ino, Some(fh), 2), Ok(()));
+        assert_eq!(netfuse.flush_file(&caller(), fh), Ok(()));
+        assert_eq!(fs.data("/a.txt"), Some(b"ne".to_vec()));
+
+        // truncating a file that isn't open writes it back right away
+        let ino = lookup(&mut netfuse, "/a.txt");
+        netfuse.file_handles.clear();
+        netfuse.cache.clear();
+        assert_eq!(netfuse.truncate(&caller(), ino, None, 1), Ok(()));
+        assert_eq!(fs.data("/a.txt"), Some(b"n".to_vec()));
+        assert!(!netfuse.cache.contains_key(&(ino, None)));
+    }
+
+    // Opens `path` for writing and writes `data` to it, then changes the file on the backend
+    fn conflicting_write(netfuse: &mut NetFuse<MemoryFs>, fs: &MemoryFs, path: &str, data: &[u8]) -> u64 {
+        let ino = lookup(netfuse, path);
+        let fh = netfuse.open_file(&caller(), ino, O_RDWR as u32).unwrap();
+        assert!(netfuse.read_file(&caller(), fh, 0, 10).is_ok());
+        assert_eq!(netfuse.write_file(&caller(), ino, fh, 0, data), Ok(data.len() as u32));
+        fs.state.lock().unwrap().files.insert(PathBuf::from(path), (b"remote".to_vec(), 2));
+        fh
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let fs = MemoryFs::with_file("/a.txt", b"old");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let fh = conflicting_write(&mut netfuse, &fs, "/a.txt", b"local");
+        assert_eq!(netfuse.flush_file(&caller(), fh), Err(ESTALE));
+        assert_eq!(fs.data("/a.txt"), Some(b"remote".to_vec()));
+
+        let fs = MemoryFs::with_file("/a.txt", b"old");
+        let mut netfuse = self::netfuse(&fs, MountOptions::new(&"/mnt").conflict_policy(ConflictPolicy::Overwrite));
+        let fh = conflicting_write(&mut netfuse, &fs, "/a.txt", b"local");
+        assert_eq!(netfuse.flush_file(&caller(), fh), Ok(()));
+        assert_eq!(fs.data("/a.txt"), Some(b"local".to_vec()));
+    }
+
+    #[test]
+    fn test_conflict_save_copy() {
+        let fs = MemoryFs::with_file("/a.txt", b"old");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt").conflict_policy(ConflictPolicy::SaveCopy));
+        let fh = conflicting_write(&mut netfuse, &fs, "/a.txt", b"local");
+
+        // closing without flush writes back on release, which drops the discarded entry
+        assert_eq!(netfuse.release_file(&caller(), fh), Ok(()));
+        assert_eq!(fs.data("/a.txt"), Some(b"remote".to_vec()));
+        let copies: Vec<_> = fs.state.lock().unwrap().files.iter()
+            .filter(|&(path, _)| path.to_string_lossy().starts_with("/a.txt.conflict-"))
+            .map(|(_, (data, _))| data.clone())
+            .collect();
+        assert_eq!(copies, vec![b"local".to_vec()]);
+        assert!(netfuse.cache.is_empty());
+
+        // the next open reads the remote changes
+        let ino = lookup(&mut netfuse, "/a.txt");
+        let fh = netfuse.open_file(&caller(), ino, O_RDONLY as u32).unwrap();
+        assert_eq!(netfuse.read_file(&caller(), fh, 0, 10), Ok(&b"remote"[..]));
+    }
+
+    #[test]
+    fn test_eviction_keeps_children_being_listed() {
+        let fs = MemoryFs::with_file("/d/a.txt", b"a");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt").max_inodes(3));
+        let dir = Metadata { kind: FileType::Directory, ..fs.metadata(Path::new("/d/a.txt")).unwrap() };
+        let ino = netfuse.inodes.insert_metadata("/d", &dir).attr.ino;
+        netfuse.inodes.add_lookup(ino);
+        for name in &["/d/a.txt", "/d/b.txt", "/d/c.txt"] {
+            netfuse.inodes.insert_metadata(name, &fs.metadata(Path::new("/d/a.txt")).unwrap());
+        }
+
+        let listing: DirIterator = Box::new(::std::iter::empty());
+        netfuse.dir_handles.insert(1, DirHandle::new(ino, Some(listing), None));
+        netfuse.inodes_changed();
+        assert_eq!(netfuse.inodes.children(ino).len(), 3);
+
+        netfuse.dir_handles.clear();
+        netfuse.inodes_changed();
+        assert!(netfuse.inodes.len() <= 3);
+        assert!(!netfuse.inodes[ino].visited);
+    }
+
+    #[test]
+    fn test_offline_access_and_reads() {
+        let fs = MemoryFs::with_file("/a.txt", b"abc");
+        let offline = OfflineOptions { failures: 1, probe_interval: Duration::from_secs(60), ..OfflineOptions::default() };
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt").offline(offline));
+        let ino = lookup(&mut netfuse, "/a.txt");
+        let fh = netfuse.open_file(&caller(), ino, O_RDONLY as u32).unwrap();
+        assert_eq!(netfuse.read_file(&caller(), fh, 0, 10), Ok(&b"abc"[..]));
+        assert_eq!(netfuse.release_file(&caller(), fh), Ok(()));
+        assert!(netfuse.cache.contains_key(&(ino, None)));
+
+        // the failed access check takes the backend offline, and the mode bits (0o644) decide instead
+        fs.state.lock().unwrap().access_error = Some(ETIMEDOUT);
+        let fh = netfuse.open_file(&caller(), ino, O_RDONLY as u32).unwrap();
+        assert!(!netfuse.backend_available());
+        assert_eq!(netfuse.read_file(&caller(), fh, 0, 10), Ok(&b"abc"[..]));
+        let other = Caller::new(1001, 1001, 43);
+        assert_eq!(netfuse.check_access(&other, Path::new("/a.txt"), W_OK), Err(EACCES));
+        assert_eq!(netfuse.check_access(&other, Path::new("/a.txt"), R_OK), Ok(()));
+        assert_eq!(netfuse.check_access(&other, Path::new("/b.txt"), R_OK), Err(EIO));
+    }
+
+    #[test]
+    fn test_offline_cache_is_bounded() {
+        let fs = MemoryFs::with_file("/a.txt", b"abc");
+        fs.state.lock().unwrap().files.insert(PathBuf::from("/b.txt"), (b"def".to_vec(), 1));
+        let offline = OfflineOptions { max_bytes: 5, ..OfflineOptions::default() };
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt").offline(offline));
+        let a = lookup(&mut netfuse, "/a.txt");
+        let b = lookup(&mut netfuse, "/b.txt");
+
+        // the data of the file closed longest ago goes once the kept data is over the limit
+        for &ino in &[a, b, a] {
+            let fh = netfuse.open_file(&caller(), ino, O_RDONLY as u32).unwrap();
+            assert!(netfuse.read_file(&caller(), fh, 0, 10).is_ok());
+            assert_eq!(netfuse.release_file(&caller(), fh), Ok(()));
+        }
+        assert!(netfuse.cache.contains_key(&(a, None)));
+        assert!(!netfuse.cache.contains_key(&(b, None)));
+        assert_eq!(netfuse.kept_bytes, 3);
+    }
+
+    #[test]
+    fn test_isolated_users_check_cached_inodes() {
+        // neither the default access hook nor default permissions let cached inodes leak between users
+        for &default_permissions in &[false, true] {
+            let fs = MemoryFs::with_file("/a.txt", b"abc");
+            fs.state.lock().unwrap().hidden_from = Some(1001);
+            let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt").default_permissions(default_permissions));
+            netfuse.isolate_users = true;
+            let other = Caller::new(1001, 1001, 43);
+            let ino = netfuse.lookup_child(&caller(), 1, Path::new("a.txt")).unwrap();
+            assert_eq!(netfuse.get_attr(&caller(), ino).map(|attr| attr.size), Ok(3));
+
+            // another user the backend hides the file from doesn't get the cached attributes or listing
+            assert_eq!(netfuse.get_attr(&other, ino).map(|attr| attr.size), Err(ENOENT));
+            assert_eq!(netfuse.lookup_child(&other, 1, Path::new("a.txt")), Err(ENOENT));
+            assert_eq!(netfuse.list_to_cache(&other, 1).map(|listed| listed.len()), Ok(0));
+
+            // once the backend shows them the file, it is served from the cache
+            fs.state.lock().unwrap().hidden_from = None;
+            assert_eq!(netfuse.get_attr(&other, ino).map(|attr| attr.size), Ok(3));
+            let lookups = fs.state.lock().unwrap().lookups;
+            assert_eq!(netfuse.get_attr(&other, ino).map(|attr| attr.size), Ok(3));
+            assert_eq!(fs.state.lock().unwrap().lookups, lookups);
+        }
+    }
+
+    // Network listing of files named after `names` that counts how many entries were pulled
+    fn counted_listing(fs: &MemoryFs, names: Vec<&'static str>, pulled: &Rc<Cell<usize>>) -> DirIterator {
+        let metadata = fs.metadata(Path::new("/d/a.txt")).unwrap();
+        let pulled = pulled.clone();
+        Box::new(names.into_iter().map(move |name| {
+            pulled.set(pulled.get() + 1);
+            Ok(DirEntry::new(name, metadata.clone()))
+        }))
+    }
+
+    // Lists up to `max` entries of `ino` after `offset` through `fh`, returning their cookies and names
+    fn list(netfuse: &mut NetFuse<MemoryFs>, ino: u64, fh: u64, offset: u64, max: usize) -> Result<Vec<(u64, String)>, LibcError> {
+        let mut listed = Vec::new();
+        netfuse.read_dir(&caller(), ino, fh, offset, &mut |_, cookie, _, name| {
+            if listed.len() == max {
+                return true;
+            }
+            listed.push((cookie, name.to_string_lossy().into_owned()));
+            false
+        })?;
+        Ok(listed)
+    }
+
+    #[test]
+    fn test_readdir_streams_listing() {
+        let fs = MemoryFs::with_file("/d/a.txt", b"a");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let dir = Metadata { kind: FileType::Directory, ..fs.metadata(Path::new("/d/a.txt")).unwrap() };
+        let ino = netfuse.inodes.insert_metadata("/d", &dir).attr.ino;
+        let pulled = Rc::new(Cell::new(0));
+        let entries = counted_listing(&fs, vec!["a.txt", "b.txt", "c.txt", "d.txt"], &pulled);
+        netfuse.dir_handles.insert(1, DirHandle::new(ino, Some(entries), None));
+
+        // entries are pulled as replies fill up, with one left over in the inode store
+        let first = list(&mut netfuse, ino, 1, 0, 3).unwrap();
+        let names: Vec<&str> = first.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(names, vec![".", "..", "a.txt"]);
+        assert_eq!(pulled.get(), 2);
+        assert!(!netfuse.inodes[ino].visited);
+
+        let second = list(&mut netfuse, ino, 1, first[2].0, 2).unwrap();
+        let names: Vec<&str> = second.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(names, vec!["b.txt", "c.txt"]);
+        assert_eq!(pulled.get(), 4);
+        assert!(netfuse.dir_handles[&1].entries.is_some());
+
+        let rest = list(&mut netfuse, ino, 1, second[1].0, 10).unwrap();
+        let names: Vec<&str> = rest.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(names, vec!["d.txt"]);
+        assert_eq!(pulled.get(), 4);
+        assert!(netfuse.inodes[ino].visited);
+        assert!(netfuse.dir_handles[&1].entries.is_none());
+    }
+
+    // Network listing of `a.txt`, an entry failing with EIO and `b.txt`
+    fn failing_listing(fs: &MemoryFs) -> DirIterator {
+        let metadata = fs.metadata(Path::new("/d/a.txt")).unwrap();
+        Box::new(vec![
+            Ok(DirEntry::new("a.txt", metadata.clone())),
+            Err(EIO),
+            Ok(DirEntry::new("b.txt", metadata)),
+        ].into_iter())
+    }
+
+    #[test]
+    fn test_readdir_error_policies() {
+        let fs = MemoryFs::with_file("/d/a.txt", b"a");
+        let dir = Metadata { kind: FileType::Directory, ..fs.metadata(Path::new("/d/a.txt")).unwrap() };
+
+        // an aborted listing keeps failing instead of carrying on past the bad entry
+        let mut netfuse = self::netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = netfuse.inodes.insert_metadata("/d", &dir).attr.ino;
+        netfuse.dir_handles.insert(1, DirHandle::new(ino, Some(failing_listing(&fs)), None));
+        assert_eq!(list(&mut netfuse, ino, 1, 0, 10), Err(EIO));
+        assert_eq!(list(&mut netfuse, ino, 1, 3, 10), Err(EIO));
+        assert!(!netfuse.inodes[ino].visited);
+
+        // skipped entries are left out and counted
+        let skipped = SkippedEntries::new();
+        let options = MountOptions::new(&"/mnt").readdir_errors(ReaddirErrorPolicy::Skip).skipped_entries(&skipped);
+        let mut netfuse = self::netfuse(&fs, options);
+        let ino = netfuse.inodes.insert_metadata("/d", &dir).attr.ino;
+        netfuse.dir_handles.insert(1, DirHandle::new(ino, Some(failing_listing(&fs)), None));
+        let listed = list(&mut netfuse, ino, 1, 0, 10).unwrap();
+        let names: Vec<&str> = listed.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(names, vec![".", "..", "a.txt", "b.txt"]);
+        assert!(netfuse.inodes[ino].visited);
+        assert_eq!(skipped.count(), 1);
+    }
+
+    #[test]
+    fn test_failed_write_back_is_retried() {
+        let fs = MemoryFs::with_file("/a.txt", b"old");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = lookup(&mut netfuse, "/a.txt");
+        let fh = netfuse.open_file(&caller(), ino, O_WRONLY as u32).unwrap();
+        assert_eq!(netfuse.write_file(&caller(), ino, fh, 0, b"new"), Ok(3));
+
+        // each close tries the upload and reports its failure, and the data stays cached after release
+        fs.state.lock().unwrap().write_error = Some(ETIMEDOUT);
+        assert_eq!(netfuse.flush_file(&caller(), fh), Err(ETIMEDOUT));
+        assert_eq!(netfuse.flush_file(&caller(), fh), Err(ETIMEDOUT));
+        assert_eq!(netfuse.release_file(&caller(), fh), Ok(()));
+        assert!(netfuse.cache.contains_key(&(ino, None)));
+        assert_eq!(fs.data("/a.txt"), Some(b"old".to_vec()));
+
+        // nothing is retried before the retry interval is over
+        fs.state.lock().unwrap().write_error = None;
+        netfuse.retry_failed_writes();
+        assert_eq!(fs.data("/a.txt"), Some(b"old".to_vec()));
+
+        let failed = Instant::now().checked_sub(WRITE_RETRY_INTERVAL).unwrap();
+        netfuse.failed_writes.get_mut(&(ino, None)).unwrap().1 = failed;
+        netfuse.retry_failed_writes();
+        assert_eq!(fs.data("/a.txt"), Some(b"new".to_vec()));
+        assert!(netfuse.failed_writes.is_empty());
+    }
+
+    #[test]
+    fn test_release_with_several_handles() {
+        let fs = MemoryFs::with_file("/a.txt", b"data");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = lookup(&mut netfuse, "/a.txt");
+        let first = netfuse.open_file(&caller(), ino, O_WRONLY as u32).unwrap();
+        let second = netfuse.open_file(&caller(), ino, O_WRONLY as u32).unwrap();
+        assert_eq!(netfuse.write_file(&caller(), ino, second, 0, b"new"), Ok(3));
+
+        // the data stays cached until the last handle is released
+        assert_eq!(netfuse.release_file(&caller(), first), Ok(()));
+        assert!(netfuse.cache.contains_key(&(ino, None)));
+        assert_eq!(netfuse.release_file(&caller(), second), Ok(()));
+        assert!(!netfuse.cache.contains_key(&(ino, None)));
+        assert_eq!(fs.data("/a.txt"), Some(b"newa".to_vec()));
+    }
+
+    #[test]
+    fn test_release_after_remove() {
+        let fs = MemoryFs::with_file("/a.txt", b"data");
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        let ino = lookup(&mut netfuse, "/a.txt");
+        let fh = netfuse.open_file(&caller(), ino, O_WRONLY as u32).unwrap();
+        assert_eq!(netfuse.write_file(&caller(), ino, fh, 0, b"new"), Ok(3));
+
+        // the open file keeps its cached data, but can't be written to or back anymore
+        fs.state.lock().unwrap().files.remove(Path::new("/a.txt"));
+        netfuse.remove_inode(ino);
+        assert!(netfuse.cache.contains_key(&(ino, None)));
+        assert_eq!(netfuse.write_file(&caller(), ino, fh, 0, b"again"), Err(ENOENT));
+        assert_eq!(netfuse.flush_file(&caller(), fh), Ok(()));
+        assert_eq!(netfuse.release_file(&caller(), fh), Ok(()));
+        assert!(!netfuse.cache.contains_key(&(ino, None)));
+        assert_eq!(fs.data("/a.txt"), None);
+    }
+
+    #[test]
+    fn test_journaled_changes_until_replayed() {
+        let fs = MemoryFs::with_file("/a.txt", b"a");
+        fs.state.lock().unwrap().files.insert(PathBuf::from("/b.txt"), (b"b".to_vec(), 1));
+        fs.state.lock().unwrap().write_error = Some(ECONNREFUSED);
+        let dir = ::std::env::temp_dir().join(format!("netfuse-lib-journal-test-{}", process::id()));
+        let _ = ::std::fs::remove_dir_all(&dir);
+        let mut netfuse = netfuse(&fs, MountOptions::new(&"/mnt"));
+        netfuse.journal = Journal::open(&dir, &fs, Duration::from_millis(1)).unwrap();
+
+        // a journaled unlink isn't undone by the backend, and a journaled file exists before it's replayed
+        assert_eq!(netfuse.apply_change(JournalOp::Unlink, &caller(), Path::new("/a.txt")), Ok(()));
+        netfuse.journal.as_mut().unwrap().write(&caller(), Path::new("/c.txt"), b"new").unwrap();
+        assert_eq!(netfuse.lookup_child(&caller(), 1, Path::new("a.txt")), Err(ENOENT));
+        let ino = netfuse.lookup_child(&caller(), 1, Path::new("c.txt")).unwrap();
+        assert_eq!(netfuse.inodes[ino].attr.size, 3);
+
+        // listings agree, and the journaled file isn't evicted
+        let mut listed: Vec<PathBuf> = netfuse.list_to_cache(&caller(), 1).unwrap().into_iter()
+            .map(|ino| netfuse.inodes[ino].path.clone())
+            .collect();
+        listed.sort();
+        assert_eq!(listed, vec![PathBuf::from("/b.txt"), PathBuf::from("/c.txt")]);
+        netfuse.inodes.forget(ino, 1);
+        netfuse.max_inodes = Some(1);
+        netfuse.inodes_changed();
+        assert!(netfuse.inodes.get(ino).is_some());
+
+        // directories with cached or journaled children can't be removed
+        let metadata = fs.metadata(Path::new("/b.txt")).unwrap();
+        let d = netfuse.inodes.insert_metadata("/d", &Metadata { kind: FileType::Directory, ..metadata.clone() }).attr.ino;
+        netfuse.inodes.insert_metadata("/d/e.txt", &metadata);
+        assert_eq!(netfuse.check_journaled_rmdir(Some(d), Path::new("/d")), Err(ENOTEMPTY));
+        netfuse.journal.as_mut().unwrap().write(&caller(), Path::new("/f/g.txt"), b"g").unwrap();
+        assert_eq!(netfuse.check_journaled_rmdir(None, Path::new("/f")), Err(ENOTEMPTY));
+        assert_eq!(netfuse.check_journaled_rmdir(None, Path::new("/empty")), Ok(()));
+
+        netfuse.journal = None;
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
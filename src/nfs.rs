@@ -1,14 +1,25 @@
 use fuse::FileType;
-use libc::{self, ENOSYS};
+use libc::{self, ECONNABORTED, ECONNREFUSED, ECONNRESET, EHOSTUNREACH, EIO, ENETDOWN, ENETUNREACH, ENOSYS, ENOTCONN, ETIMEDOUT};
 use time::Timespec;
+use std::fs;
 use std::path::Path;
 use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStringExt;
 
 /// libc Error Code
 pub type LibcError = libc::c_int;
 
+/// Whether `err` means that the backend couldn't be reached, rather than that it failed the operation
+///
+/// Backends should report network failures as `EIO`, `ETIMEDOUT`, `ENOTCONN` or one of the
+/// connection and network errors, so that `NetFuse` tries again later where it can.
+pub fn is_unreachable(err: LibcError) -> bool {
+    [EIO, ETIMEDOUT, ENOTCONN, ECONNABORTED, ECONNREFUSED, ECONNRESET, ENETDOWN, ENETUNREACH, EHOSTUNREACH]
+        .contains(&err)
+}
+
 /// Metadata representing a file
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Metadata {
     pub size: u64,
     pub atime: Timespec,
@@ -17,8 +28,88 @@ pub struct Metadata {
     pub crtime: Timespec,
     pub kind: FileType,
     pub perm: u16,
+    /// Opaque identifier of this version of the file (e.g. an ETag), if the backend has one
+    ///
+    /// When present, `NetFuse` passes it to `NetworkFilesystem::revalidate`
+    /// to check whether its cached metadata and data are still current.
+    pub version: Option<String>,
+    /// Unique identifier of the object in the backend, if it has one
+    ///
+    /// `NetFuse` uses it as the inode number where possible, so an object keeps its inode number
+    /// when it is renamed, and is recognized as the same file when reached through several paths.
+    /// A previous path stays an alias of the object until a listing of its directory no longer has it.
+    pub id: Option<u64>,
+    /// Number of 512-byte blocks allocated to the file, if the backend knows it
+    ///
+    /// When absent, it is computed from `size`.
+    pub blocks: Option<u64>,
+    /// Name of the user owning the file in the backend, if it tracks owners
+    ///
+    /// Mapped to a local uid with the mount's `IdMap`. Without one, or when absent,
+    /// files are owned by the uid the volume is mounted with.
+    pub owner: Option<String>,
+    /// Name of the group owning the file in the backend, mapped like `owner`
+    pub group: Option<String>,
+}
+
+/// Outcome of checking a cached version of a file against the backend
+#[derive(Debug, Clone, PartialEq)]
+pub enum Revalidation {
+    /// The file still has the given version
+    Unchanged,
+    /// The file has changed, and this is its current metadata
+    Changed(Metadata),
+    /// The file no longer exists
+    Gone,
+}
+
+/// Process that made a filesystem request
+///
+/// Passed to the `NetworkFilesystem` methods that serve a request, so that a backend can
+/// authorize, audit or pick credentials per local user. Work that `NetFuse` does in the
+/// background, like prefetching, is done on behalf of the caller of the request that started it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    /// Arguments of the calling process, if enabled with `MountOptions::caller_cmdline`
+    /// and the process was still running
+    pub cmdline: Option<Vec<OsString>>,
 }
 
+impl Caller {
+    pub fn new(uid: u32, gid: u32, pid: u32) -> Caller {
+        Caller { uid: uid, gid: gid, pid: pid, cmdline: None }
+    }
+
+    /// Reads the command line of the process from `/proc`
+    pub fn read_cmdline(&mut self) {
+        self.cmdline = fs::read(format!("/proc/{}/cmdline", self.pid)).ok().map(|cmdline| {
+            cmdline.split(|&byte| byte == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| OsString::from_vec(arg.to_vec()))
+                .collect()
+        });
+    }
+}
+
+/// How `NetFuse` resolves a name that is not cached in a directory that has not been listed
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LookupStrategy {
+    /// Call `NetworkFilesystem::lookup` for the name
+    Stat,
+    /// List the whole directory with `NetworkFilesystem::readdir`, caching every entry,
+    /// and answer from the listing. Suits backends where listing is cheaper than many lookups.
+    Readdir,
+}
+
+/// Iterator over the entries of a directory listing
+///
+/// The iterator is owned by `NetFuse` for as long as the directory is open,
+/// so it must not borrow from the `NetworkFilesystem` that created it.
+pub type DirIterator = Box<dyn Iterator<Item = Result<DirEntry, LibcError>>>;
+
 /// Entry from a directory listing
 #[derive(Debug, Clone, PartialEq)]
 pub struct DirEntry {
@@ -53,6 +144,53 @@ pub trait NetworkFilesystem {
         Ok(())
     }
 
+    /// Any arbitrary code to run when unmounting, e.g. closing connections or writing checkpoints
+    ///
+    /// Called after cached data was written back. Returning an error only logs it.
+    fn destroy(&mut self) -> Result<(), LibcError> {
+        Ok(())
+    }
+
+    /// Hint for how names missing from the inode cache should be resolved
+    ///
+    /// Defaults to `LookupStrategy::Stat`. A strategy set in `MountOptions` takes precedence.
+    fn lookup_strategy(&self) -> LookupStrategy {
+        LookupStrategy::Stat
+    }
+
+    /// Returns a separate handle to this backend for reading files in the background
+    ///
+    /// When prefetching is enabled with `MountOptions::prefetch`, this is called once for each
+    ///   prefetch worker thread, and only `read` is called on the returned handles.
+    ///   When pinning is enabled with `MountOptions::pins`, it is also called once to sync
+    ///   pinned paths, and `lookup`, `readdir` and `read` are called on that handle.
+    ///   The default returns `None`, which leaves prefetching and pinning disabled.
+    fn clone_for_prefetch(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+        None
+    }
+
+    /// Returns a separate handle to this backend for replaying the write journal in the background
+    ///
+    /// When a journal is enabled with `MountOptions::journal`, this is called once when mounting,
+    ///   and only `write`, `mkdir`, `rmdir` and `unlink` are called on the returned handle.
+    ///   The default returns `None`, which leaves the journal disabled.
+    fn clone_for_journal(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+        None
+    }
+
+    /// Whether users of the volume must be kept from seeing each other's cached data
+    ///
+    /// Return true if the backend answers each user differently, e.g. with per-user credentials.
+    ///   `NetFuse` then caches file data separately for each uid, and lists directories with
+    ///   each user's `Caller` instead of answering from another user's listing. Prefetching is disabled.
+    ///   Cached metadata is only served to a user once the backend has shown them the file,
+    ///   in a lookup or listing made with their `Caller`, so this doesn't depend on `check_access`.
+    ///
+    /// Defaults to false.
+    fn isolate_users(&self) -> bool {
+        false
+    }
+
     /// Returns the metadata for a file or directory associated with a given
     ///
     /// This typically corresponds to operations like `stat`.
@@ -60,10 +198,38 @@ pub trait NetworkFilesystem {
     /// cached inode data for this `path`. Any returned `Metadata` will be heavily cached.
     ///
     /// See `man 2 stat` for more information including appropriate errors to return.
-    fn lookup(&mut self, _path: &Path) -> Result<Metadata, LibcError> {
+    fn lookup(&mut self, _caller: &Caller, _path: &Path) -> Result<Metadata, LibcError> {
+        Err(ENOSYS)
+    }
+
+    /// Checks whether the file at `path` still has the given `version`
+    ///
+    /// This is called for inodes whose `Metadata` included a `version`, when opening a file
+    ///   with data still in the cache, and when cached metadata is older than the mount's
+    ///   revalidation TTL. Returning `Changed` discards cached data that has no local changes.
+    ///
+    /// The default returns `ENOSYS`, in which case the cached metadata and data are kept.
+    fn revalidate(&mut self, _caller: &Caller, _path: &Path, _version: &str) -> Result<Revalidation, LibcError> {
         Err(ENOSYS)
     }
 
+    /// Checks whether the caller may access the file at `path`
+    ///
+    /// `mode` is `libc::F_OK` to check that the file exists, or a mask of `libc::R_OK`,
+    ///   `libc::W_OK` and `libc::X_OK`. Return `Err(EACCES)` to deny access.
+    ///
+    /// Besides answering `access` calls, this is called before opening a file, with the access
+    ///   mode it is opened with, before truncating a file, and with `W_OK | X_OK` on the parent
+    ///   directory before creating or removing an entry. It is not called on volumes mounted with
+    ///   `MountOptions::default_permissions`, where the kernel checks the mode bits instead.
+    ///
+    /// The default allows every access.
+    ///
+    /// See `man 2 access` for more information including appropriate errors to return.
+    fn check_access(&mut self, _caller: &Caller, _path: &Path, _mode: u32) -> Result<(), LibcError> {
+        Ok(())
+    }
+
     /// Reads the contents of a file associated with a given path
     ///
     /// This is called on the first filesystem attempt to `read` a file,
@@ -74,7 +240,7 @@ pub trait NetworkFilesystem {
     /// The cached data will be freed when there are no remaining open handles on this file.
     ///
     /// See `man 2 read` for more information including appropriate errors to return.
-    fn read(&mut self, _path: &Path, _buffer: &mut Vec<u8> ) -> Result<usize, LibcError> {
+    fn read(&mut self, _caller: &Caller, _path: &Path, _buffer: &mut Vec<u8> ) -> Result<usize, LibcError> {
         Err(ENOSYS)
     }
 
@@ -87,30 +253,38 @@ pub trait NetworkFilesystem {
     /// Note: the `release` implementation might change in the future in favor of
     ///   a configurable defferred commit
     ///
+    /// `version` is the `Metadata::version` of the file when its data was read into the cache,
+    ///   or `None` for new files and unconditional writes. If it is given and the file no longer
+    ///   has that version, return `Err(ESTALE)` without writing; `NetFuse` then applies the
+    ///   mount's `ConflictPolicy`. On success, return the new version of the file, if any.
+    ///
     /// This method will only be called if:
     /// - a previous `lookup` has confirmed a file exists at this path
     /// - the volume was mounted with the `rw` option
     ///
     /// See `man 2 fsync` for more information including appropriate errors to return.
-    fn write(&mut self, _path: &Path, _data: &[u8]) -> Result<(), LibcError> {
+    fn write(&mut self, _caller: &Caller, _path: &Path, _data: &[u8], _version: Option<&str>) -> Result<Option<String>, LibcError> {
         Err(ENOSYS)
     }
 
     /// List contents of a directory
     ///
     /// This method should return an iterator over the contents of the directory
-    ///   specified by `path`. By returning an iterator, `NetFuse` can begin listing
-    ///   contents sooner in the cases where listing may require multiple paged network requests.
+    ///   specified by `path`. `NetFuse` requests this iterator when the directory is opened
+    ///   and only advances it as the kernel asks for more entries, so an implementation
+    ///   that fetches one page per network request can begin listing contents right away.
+    ///   The iterator is dropped when the directory is closed.
+    ///
+    /// An `Err` item fails the `readdir` call, unless the volume was mounted with
+    ///   `ReaddirErrorPolicy::Skip`, in which case only that entry is left out of the listing.
     ///
     /// These value will be cached to prevent additional listing. The current cache implementation
     ///   will likely change as it is not friendly to cases where the data changes outside the filesystem.
     ///   Until then, unmount and re-mount the volume to clear the directory listing cache.
     ///
     /// See `man 2 readdir` for more information including appropriate errors to return.
-    ///
-    /// Note: this method will likely return `impl Iterator<Item=Result<DirEntry, LibcError>>` once `impl Trait` lands in nightly
-    fn readdir(&mut self, _path: &Path) -> Vec<Result<DirEntry, LibcError>> {
-        vec![Err(ENOSYS)]
+    fn readdir(&mut self, _caller: &Caller, _path: &Path) -> DirIterator {
+        Box::new(Some(Err(ENOSYS)).into_iter())
     }
 
     /// Creates an empty directory for the given path
@@ -120,7 +294,18 @@ pub trait NetworkFilesystem {
     /// - the volume is mounted with the `rw` option
     ///
     /// See `man 2 mkdir` for more information including appropriate errors to return.
-    fn mkdir(&mut self, _path: &Path) -> Result<(), LibcError> {
+    fn mkdir(&mut self, _caller: &Caller, _path: &Path) -> Result<(), LibcError> {
+        Err(ENOSYS)
+    }
+
+    /// Changes the owner and/or group of the file at `path`
+    ///
+    /// This is called when the owner of a file is changed on a volume mounted with
+    ///   `MountOptions::id_map`, with the remote names the new local ids map back to.
+    ///   `None` leaves the owner or group unchanged.
+    ///
+    /// See `man 2 chown` for more information including appropriate errors to return.
+    fn chown(&mut self, _caller: &Caller, _path: &Path, _owner: Option<&str>, _group: Option<&str>) -> Result<(), LibcError> {
         Err(ENOSYS)
     }
 
@@ -132,7 +317,7 @@ pub trait NetworkFilesystem {
     ///
     /// See `man 2 rmdir` for more information including appropriate errors to return.
     ///   Namely: you'll generally want to return ENOTEMPTY if the directory is not empty
-    fn rmdir(&mut self, _path: &Path) -> Result<(), LibcError> {
+    fn rmdir(&mut self, _caller: &Caller, _path: &Path) -> Result<(), LibcError> {
         Err(ENOSYS)
     }
 
@@ -143,7 +328,7 @@ pub trait NetworkFilesystem {
     /// - the volume is mounted with the `rw` option
     ///
     /// See `man 2 unlink` for more information including appropriate errors to return.
-    fn unlink(&mut self, _path: &Path) -> Result<(), LibcError>{
+    fn unlink(&mut self, _caller: &Caller, _path: &Path) -> Result<(), LibcError>{
         Err(ENOSYS)
     }
 
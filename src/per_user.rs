@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::path::Path;
+use super::{Caller, DirIterator, LibcError, LookupStrategy, Metadata, NetworkFilesystem, Revalidation};
+
+/// Connects local users to the backend with their own credentials
+///
+/// Implemented for closures that take the `Caller` and return a `NetworkFilesystem`.
+pub trait CredentialProvider {
+    type Filesystem: NetworkFilesystem;
+
+    /// Creates the backend used for requests from `caller.uid`, e.g. with that user's API key
+    ///
+    /// This is called on the first request from each uid, and the backend is kept for the rest
+    ///   of the mount. Return `Err(EACCES)` for users without credentials; their requests fail
+    ///   with that error, and the next request tries to connect again.
+    fn connect(&mut self, caller: &Caller) -> Result<Self::Filesystem, LibcError>;
+}
+
+impl<F, NFS> CredentialProvider for F
+    where F: FnMut(&Caller) -> Result<NFS, LibcError>, NFS: NetworkFilesystem
+{
+    type Filesystem = NFS;
+
+    fn connect(&mut self, caller: &Caller) -> Result<NFS, LibcError> {
+        self(caller)
+    }
+}
+
+/// `NetworkFilesystem` that serves each local user from a backend with that user's credentials
+///
+/// Requests are forwarded to the backend the `CredentialProvider` created for the calling uid.
+/// Users are isolated from each other as described for `NetworkFilesystem::isolate_users`,
+/// and access is checked with the calling user's backend. Mount with `MountOptions::allow_other`
+/// so that other users can reach the volume.
+pub struct PerUserFilesystem<P: CredentialProvider> {
+    provider: P,
+    backends: HashMap<u32, P::Filesystem>,
+}
+
+impl<P: CredentialProvider> PerUserFilesystem<P> {
+    pub fn new(provider: P) -> PerUserFilesystem<P> {
+        PerUserFilesystem {
+            provider: provider,
+            backends: HashMap::new(),
+        }
+    }
+
+    // Backend of the calling user, connected and initialized on first use
+    fn backend(&mut self, caller: &Caller) -> Result<&mut P::Filesystem, LibcError> {
+        match self.backends.entry(caller.uid) {
+            Entry::Occupied(backend) => Ok(backend.into_mut()),
+            Entry::Vacant(vacant) => {
+                let mut backend = self.provider.connect(caller).inspect_err(|err| {
+                    warn!("failed to connect backend for uid {} - {}", caller.uid, err);
+                })?;
+                backend.init()?;
+                info!("connected backend for uid {}", caller.uid);
+                Ok(vacant.insert(backend))
+            }
+        }
+    }
+}
+
+impl<P: CredentialProvider> fmt::Debug for PerUserFilesystem<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut uids: Vec<&u32> = self.backends.keys().collect();
+        uids.sort();
+        f.debug_struct("PerUserFilesystem")
+            .field("uids", &uids)
+            .finish()
+    }
+}
+
+impl<P: CredentialProvider> NetworkFilesystem for PerUserFilesystem<P> {
+    fn lookup_strategy(&self) -> LookupStrategy {
+        // Listings aren't shared between users, so only list when asked to
+        LookupStrategy::Stat
+    }
+
+    fn isolate_users(&self) -> bool {
+        true
+    }
+
+    // Destroys every connected backend, returning the first error
+    fn destroy(&mut self) -> Result<(), LibcError> {
+        let mut result = Ok(());
+        for (uid, backend) in &mut self.backends {
+            if let Err(err) = backend.destroy() {
+                warn!("failed to destroy backend for uid {} - {}", uid, err);
+                result = result.and(Err(err));
+            }
+        }
+        result
+    }
+
+    fn lookup(&mut self, caller: &Caller, path: &Path) -> Result<Metadata, LibcError> {
+        self.backend(caller)?.lookup(caller, path)
+    }
+
+    fn revalidate(&mut self, caller: &Caller, path: &Path, version: &str) -> Result<Revalidation, LibcError> {
+        self.backend(caller)?.revalidate(caller, path, version)
+    }
+
+    fn check_access(&mut self, caller: &Caller, path: &Path, mode: u32) -> Result<(), LibcError> {
+        self.backend(caller)?.check_access(caller, path, mode)
+    }
+
+    fn read(&mut self, caller: &Caller, path: &Path, buffer: &mut Vec<u8>) -> Result<usize, LibcError> {
+        self.backend(caller)?.read(caller, path, buffer)
+    }
+
+    fn write(&mut self, caller: &Caller, path: &Path, data: &[u8], version: Option<&str>) -> Result<Option<String>, LibcError> {
+        self.backend(caller)?.write(caller, path, data, version)
+    }
+
+    fn readdir(&mut self, caller: &Caller, path: &Path) -> DirIterator {
+        match self.backend(caller) {
+            Ok(backend) => backend.readdir(caller, path),
+            Err(err) => Box::new(Some(Err(err)).into_iter()),
+        }
+    }
+
+    fn mkdir(&mut self, caller: &Caller, path: &Path) -> Result<(), LibcError> {
+        self.backend(caller)?.mkdir(caller, path)
+    }
+
+    fn chown(&mut self, caller: &Caller, path: &Path, owner: Option<&str>, group: Option<&str>) -> Result<(), LibcError> {
+        self.backend(caller)?.chown(caller, path, owner, group)
+    }
+
+    fn rmdir(&mut self, caller: &Caller, path: &Path) -> Result<(), LibcError> {
+        self.backend(caller)?.rmdir(caller, path)
+    }
+
+    fn unlink(&mut self, caller: &Caller, path: &Path) -> Result<(), LibcError> {
+        self.backend(caller)?.unlink(caller, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use libc::EACCES;
+
+    // Backend that reads every file as the key it was connected with
+    struct KeyFs(String);
+
+    impl NetworkFilesystem for KeyFs {
+        fn read(&mut self, _caller: &Caller, _path: &Path, buffer: &mut Vec<u8>) -> Result<usize, LibcError> {
+            buffer.extend_from_slice(self.0.as_bytes());
+            Ok(self.0.len())
+        }
+    }
+
+    #[test]
+    fn test_per_user_backends() {
+        let mut connects = 0;
+        let mut fs = PerUserFilesystem::new(|caller: &Caller| {
+            connects += 1;
+            match caller.uid {
+                1000 => Ok(KeyFs("alice-key".into())),
+                1001 => Ok(KeyFs("bob-key".into())),
+                _ => Err(EACCES),
+            }
+        });
+        assert!(fs.isolate_users());
+
+        let (alice, bob) = (Caller::new(1000, 1000, 10), Caller::new(1001, 1001, 11));
+        for &(caller, expected) in &[(&alice, "alice-key"), (&bob, "bob-key"), (&alice, "alice-key")] {
+            let mut buffer = Vec::new();
+            fs.read(caller, Path::new("/foo.txt"), &mut buffer).unwrap();
+            assert_eq!(buffer, expected.as_bytes());
+        }
+        let mallory = Caller::new(1002, 1002, 12);
+        assert_eq!(fs.read(&mallory, Path::new("/foo.txt"), &mut Vec::new()), Err(EACCES));
+        assert_eq!(fs.readdir(&mallory, Path::new("/")).collect::<Vec<_>>(), vec![Err(EACCES)]);
+
+        drop(fs);
+        assert_eq!(connects, 4);
+    }
+}
@@ -0,0 +1,659 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use fuse::FileType;
+use libc::{self, ECANCELED, EINVAL, ENOSYS};
+use time::Timespec;
+use inode::{decode_path, encode_path};
+use super::{Caller, DirEntry, LibcError, Metadata, NetworkFilesystem};
+
+// Header line identifying a pin index
+const PINS_HEADER: &str = "netfuse-pins 1";
+
+/// Sync state of a pinned path
+#[derive(Debug, Clone, PartialEq)]
+pub enum PinStatus {
+    /// Waiting for the first sync
+    Pending,
+    /// Being fetched, with the number of files fetched so far in this sync
+    Syncing { files: u64 },
+    /// Fully fetched, with the number of files fetched by the last sync and when it finished
+    Synced { files: u64, at: SystemTime },
+    /// The last sync failed; files fetched earlier stay available
+    Failed(LibcError),
+}
+
+/// Files and directories kept available offline in a local cache directory
+///
+/// Pinned paths are fetched recursively with `NetworkFilesystem::readdir` and `read` by a
+/// background thread once the volume is mounted with `MountOptions::pins`, and refreshed every
+/// refresh interval. Pinned data is never evicted: reads of unchanged pinned files are served from
+/// the local copy, and while the backend is offline (see `MountOptions::offline`) lookups, listings
+/// and reads under pinned paths are answered from it.
+///
+/// Pins can be changed through clones of this handle while mounted, or through the mounted
+/// filesystem by setting or removing the `user.netfuse.pin` extended attribute of a file or
+/// directory (e.g. `setfattr -n user.netfuse.pin <path>`), which requires read access to it.
+/// Reading the attribute of a pinned path gives the status of its last sync as `pending`,
+/// `syncing`, `synced` or `failed`, padded with spaces to 8 bytes (e.g. `getfattr -n user.netfuse.pin <path>`).
+/// Pins and the status of their last sync are also listed in the `status` file of the cache directory.
+#[derive(Clone)]
+pub struct Pins {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    dir: PathBuf,
+    refresh_interval: Duration,
+    state: Mutex<PinState>,
+    // signaled when a path is pinned, to start syncing it
+    wakeup: Condvar,
+}
+
+#[derive(Default)]
+struct PinState {
+    roots: BTreeMap<PathBuf, Pin>,
+    // metadata of every cached file and directory under the pinned paths
+    entries: HashMap<PathBuf, Metadata>,
+    // names of the cached children of each cached directory
+    listings: HashMap<PathBuf, BTreeSet<OsString>>,
+    syncing: bool,
+}
+
+struct Pin {
+    // the user the pinned path is fetched as
+    uid: u32,
+    gid: u32,
+    status: PinStatus,
+    // when the last sync finished
+    synced: Option<Instant>,
+}
+
+impl Pins {
+    /// Opens the pin cache in `dir`, creating it if needed
+    ///
+    /// Pinned paths are refreshed every `refresh_interval`
+    pub fn open<P: AsRef<Path>>(dir: P, refresh_interval: Duration) -> io::Result<Pins> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(dir.join("files"))?;
+        let state = load_index(&dir)?;
+        Ok(Pins {
+            shared: Arc::new(Shared {
+                dir: dir,
+                refresh_interval: refresh_interval,
+                state: Mutex::new(state),
+                wakeup: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Pins `path` for the user running the process
+    pub fn pin<P: AsRef<Path>>(&self, path: P) {
+        let caller = unsafe { Caller::new(libc::getuid(), libc::getgid(), 0) };
+        self.pin_for(path.as_ref(), &caller);
+    }
+
+    /// Pins `path` for `caller`, whose credentials are used to fetch it
+    pub fn pin_for(&self, path: &Path, caller: &Caller) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.roots.contains_key(path) {
+            info!("pinned {}", path.display());
+            state.roots.insert(path.to_owned(), Pin { uid: caller.uid, gid: caller.gid, status: PinStatus::Pending, synced: None });
+            self.shared.save(&state);
+            self.shared.wakeup.notify_all();
+        }
+    }
+
+    /// Unpins `path` and removes its cached files, except those that are also under another pinned path
+    ///
+    /// Returns false if `path` wasn't pinned
+    pub fn unpin<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = path.as_ref();
+        let mut state = self.shared.state.lock().unwrap();
+        if state.roots.remove(path).is_none() {
+            return false;
+        }
+        info!("unpinned {}", path.display());
+        let removed: Vec<PathBuf> = state.entries.keys()
+            .filter(|entry| entry.starts_with(path) && !state.is_pinned(entry))
+            .cloned()
+            .collect();
+        self.shared.remove_entries(&mut state, removed);
+        self.shared.save(&state);
+        true
+    }
+
+    /// Status of the pinned `path`, or None if it isn't pinned
+    pub fn status<P: AsRef<Path>>(&self, path: P) -> Option<PinStatus> {
+        let state = self.shared.state.lock().unwrap();
+        state.roots.get(path.as_ref()).map(|pin| pin.status.clone())
+    }
+
+    /// All pinned paths with their status
+    pub fn pinned(&self) -> Vec<(PathBuf, PinStatus)> {
+        let state = self.shared.state.lock().unwrap();
+        state.roots.iter().map(|(path, pin)| (path.clone(), pin.status.clone())).collect()
+    }
+
+    // Starts syncing in the background with a handle from `clone_for_prefetch`.
+    // Returns false if the backend provides none.
+    pub(crate) fn start<NFS: NetworkFilesystem>(&self, nfs: &NFS) -> bool {
+        let fetcher = match nfs.clone_for_prefetch() {
+            Some(fetcher) => fetcher,
+            None => return false,
+        };
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.syncing {
+                return true;
+            }
+            state.syncing = true;
+        }
+        let shared = self.shared.clone();
+        thread::spawn(move || sync_pins(fetcher, &shared));
+        true
+    }
+
+    // Cached metadata of `path`, if it is under a pinned path
+    pub(crate) fn metadata(&self, path: &Path) -> Option<Metadata> {
+        self.shared.state.lock().unwrap().entries.get(path).cloned()
+    }
+
+    // Cached children of the directory `path`, if it is under a pinned path
+    pub(crate) fn children(&self, path: &Path) -> Option<Vec<DirEntry>> {
+        let state = self.shared.state.lock().unwrap();
+        match state.entries.get(path) {
+            Some(metadata) if metadata.kind == FileType::Directory => (),
+            _ => return None,
+        }
+        let names = match state.listings.get(path) {
+            Some(names) => names,
+            None => return Some(Vec::new()),
+        };
+        Some(names.iter()
+            .filter_map(|name| state.entries.get(&path.join(name)).map(|metadata| DirEntry::new(name, metadata.clone())))
+            .collect())
+    }
+
+    // Cached data of the file `path`, if it is under a pinned path and, unless `version` is None,
+    // the cached copy has that version
+    pub(crate) fn read(&self, path: &Path, version: Option<&str>) -> Option<Vec<u8>> {
+        {
+            let state = self.shared.state.lock().unwrap();
+            match state.entries.get(path) {
+                Some(metadata) if metadata.kind != FileType::Directory => {
+                    if version.is_some() && metadata.version.as_deref() != version {
+                        return None;
+                    }
+                }
+                _ => return None,
+            }
+        }
+        fs::read(self.shared.file_path(path)?).inspect_err(|err| {
+            warn!("failed to read pinned copy of {} - {}", path.display(), err);
+        }).ok()
+    }
+}
+
+impl PinState {
+    fn is_pinned(&self, path: &Path) -> bool {
+        self.roots.keys().any(|root| path.starts_with(root))
+    }
+
+    fn insert_entry(&mut self, path: PathBuf, metadata: Metadata) {
+        if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+            self.listings.entry(parent.to_owned()).or_default().insert(name.to_owned());
+        }
+        self.entries.insert(path, metadata);
+    }
+
+    fn remove_entry(&mut self, path: &Path) -> Option<Metadata> {
+        if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+            if let Some(names) = self.listings.get_mut(parent) {
+                names.remove(name);
+                if names.is_empty() {
+                    self.listings.remove(parent);
+                }
+            }
+        }
+        self.entries.remove(path)
+    }
+
+    fn set_status(&mut self, root: &Path, status: PinStatus) {
+        if let Some(pin) = self.roots.get_mut(root) {
+            pin.status = status;
+        }
+    }
+}
+
+impl Shared {
+    // Location of the cached copy of `path`, or None if `path` could lead outside the cache directory
+    fn file_path(&self, path: &Path) -> Option<PathBuf> {
+        let mut file = self.dir.join("files");
+        for component in path.components() {
+            match component {
+                Component::RootDir => (),
+                Component::Normal(name) => file.push(name),
+                _ => return None,
+            }
+        }
+        Some(file)
+    }
+
+    fn remove_entries(&self, state: &mut PinState, paths: Vec<PathBuf>) {
+        for path in paths {
+            if let (Some(metadata), Some(file)) = (state.remove_entry(&path), self.file_path(&path)) {
+                let _ = match metadata.kind {
+                    FileType::Directory => fs::remove_dir_all(&file),
+                    _ => fs::remove_file(&file),
+                };
+            }
+        }
+    }
+
+    // Writes the index and the status file, logging failures
+    fn save(&self, state: &PinState) {
+        if let Err(err) = save_index(&self.dir, state) {
+            error!("failed to save pin index in {} - {}", self.dir.display(), err);
+        }
+    }
+}
+
+impl fmt::Debug for Pins {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pins")
+            .field("dir", &self.shared.dir)
+            .field("pinned", &self.pinned())
+            .finish()
+    }
+}
+
+// Syncs pinned paths as they are pinned and once their refresh interval is over,
+// until all handles to the pins are dropped
+fn sync_pins(mut nfs: Box<dyn NetworkFilesystem + Send>, shared: &Arc<Shared>) {
+    loop {
+        let (root, caller) = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if Arc::strong_count(shared) == 1 {
+                    return;
+                }
+                let due = state.roots.iter()
+                    .find(|&(_, pin)| pin.synced.map(|synced| synced.elapsed() >= shared.refresh_interval).unwrap_or(true))
+                    .map(|(root, pin)| (root.clone(), Caller::new(pin.uid, pin.gid, 0)));
+                if let Some(due) = due {
+                    break due;
+                }
+                // wake up periodically to notice when the pins are dropped
+                let wait = shared.refresh_interval.min(Duration::from_secs(60));
+                state = shared.wakeup.wait_timeout(state, wait).unwrap().0;
+            }
+        };
+
+        shared.state.lock().unwrap().set_status(&root, PinStatus::Syncing { files: 0 });
+        let mut sync = PinSync { nfs: &mut *nfs, shared: shared, root: &root, caller: &caller, files: 0, seen: HashSet::new() };
+        let result = sync.run();
+        let (files, seen) = (sync.files, sync.seen);
+
+        let mut state = shared.state.lock().unwrap();
+        let status = match result {
+            Ok(()) => {
+                // drop what disappeared from the backend
+                let removed: Vec<PathBuf> = state.entries.keys()
+                    .filter(|entry| entry.starts_with(&root) && !seen.contains(*entry))
+                    .filter(|entry| !state.roots.keys().any(|other| other != &root && entry.starts_with(other)))
+                    .cloned()
+                    .collect();
+                shared.remove_entries(&mut state, removed);
+                info!("synced {} - fetched {} files", root.display(), files);
+                PinStatus::Synced { files: files, at: SystemTime::now() }
+            }
+            Err(ECANCELED) => continue,
+            Err(err) => {
+                warn!("sync of pinned {} failed - {}", root.display(), err);
+                PinStatus::Failed(err)
+            }
+        };
+        if let Some(pin) = state.roots.get_mut(&root) {
+            pin.status = status;
+            pin.synced = Some(Instant::now());
+        }
+        shared.save(&state);
+    }
+}
+
+// A sync of one pinned path
+struct PinSync<'a> {
+    nfs: &'a mut (dyn NetworkFilesystem + Send),
+    shared: &'a Shared,
+    root: &'a Path,
+    caller: &'a Caller,
+    // files fetched so far
+    files: u64,
+    // paths found in the backend
+    seen: HashSet<PathBuf>,
+}
+
+impl<'a> PinSync<'a> {
+    fn run(&mut self) -> Result<(), LibcError> {
+        let root = self.root.to_owned();
+        match self.nfs.lookup(self.caller, &root) {
+            Ok(ref metadata) if metadata.kind != FileType::Directory => self.fetch_file(&root, metadata),
+            Ok(metadata) => {
+                self.store(&root, metadata)?;
+                self.walk(&root)
+            }
+            // without lookups, the pinned path is assumed to be a directory
+            Err(ENOSYS) => {
+                self.store(&root, directory_metadata())?;
+                self.walk(&root)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn walk(&mut self, path: &Path) -> Result<(), LibcError> {
+        fs::create_dir_all(self.shared.file_path(path).ok_or(EINVAL)?).map_err(io_error)?;
+        let entries: Vec<DirEntry> = self.nfs.readdir(self.caller, path).collect::<Result<_, _>>()?;
+        for entry in entries {
+            // names from the backend become paths in the cache directory
+            let name = entry.filename.as_bytes();
+            if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+                warn!("skipping invalid name {:?} in pinned {}", entry.filename, path.display());
+                continue;
+            }
+            let child = path.join(&entry.filename);
+            match entry.metadata.kind {
+                FileType::Directory => {
+                    self.store(&child, entry.metadata)?;
+                    self.walk(&child)?;
+                }
+                _ => self.fetch_file(&child, &entry.metadata)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Fetches the file at `path` unless the cached copy has the version in `metadata`
+    fn fetch_file(&mut self, path: &Path, metadata: &Metadata) -> Result<(), LibcError> {
+        let cached = self.shared.state.lock().unwrap().entries.get(path).map(|cached| cached.version.clone());
+        if metadata.version.is_some() && cached == Some(metadata.version.clone()) {
+            self.seen.insert(path.to_owned());
+            return Ok(());
+        }
+
+        let mut data = Vec::new();
+        self.nfs.read(self.caller, path, &mut data)?;
+        let file = self.shared.file_path(path).ok_or(EINVAL)?;
+        let mut tmp_name = file.file_name().map(|name| name.to_owned()).unwrap_or_default();
+        tmp_name.push(".tmp");
+        let tmp_file = file.with_file_name(tmp_name);
+        {
+            let mut tmp = File::create(&tmp_file).map_err(io_error)?;
+            tmp.write_all(&data).map_err(io_error)?;
+            tmp.sync_all().map_err(io_error)?;
+        }
+        fs::rename(&tmp_file, &file).map_err(io_error)?;
+
+        let mut metadata = metadata.clone();
+        metadata.size = data.len() as u64;
+        self.store(path, metadata)?;
+        self.files += 1;
+        let files = self.files;
+        self.shared.state.lock().unwrap().set_status(self.root, PinStatus::Syncing { files: files });
+        Ok(())
+    }
+
+    // Records `metadata` for `path`, or stops the sync if the path was unpinned meanwhile
+    fn store(&mut self, path: &Path, metadata: Metadata) -> Result<(), LibcError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.roots.contains_key(self.root) {
+            return Err(ECANCELED);
+        }
+        state.insert_entry(path.to_owned(), metadata);
+        self.seen.insert(path.to_owned());
+        Ok(())
+    }
+}
+
+fn io_error(err: io::Error) -> LibcError {
+    err.raw_os_error().unwrap_or(libc::EIO)
+}
+
+fn directory_metadata() -> Metadata {
+    let now = ::time::now_utc().to_timespec();
+    Metadata {
+        size: 0,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind: FileType::Directory,
+        perm: 0o755,
+        version: None,
+        id: None,
+        blocks: None,
+        owner: None,
+        group: None,
+    }
+}
+
+// The index is a header followed by lines `pin <uid> <gid> <path>` for pinned paths,
+// `dir <perm> <mtime> <path>` for cached directories and `file <perm> <mtime> <size> <version> <path>`
+// for cached files, with `-` for files without a version. Paths and versions are escaped like in the
+// inode state file. Sync status isn't kept, so pinned paths sync again when opened.
+fn load_index(dir: &Path) -> io::Result<PinState> {
+    let mut state = PinState::default();
+    let file = match File::open(dir.join("pins")) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(state),
+        Err(err) => return Err(err),
+    };
+
+    let mut lines = BufReader::new(file).lines();
+    match lines.next() {
+        Some(Ok(ref header)) if header == PINS_HEADER => (),
+        Some(Err(err)) => return Err(err),
+        _ => return Err(invalid_index("missing header")),
+    }
+
+    for line in lines {
+        let line = line?;
+        let fields: Vec<&str> = line.split(' ').collect();
+        let parse = |field: &str| field.parse::<u64>().map_err(|_| invalid_index(&line));
+        let path = fields.last().and_then(|path| decode_path(path)).ok_or_else(|| invalid_index(&line))?;
+        match (fields[0], fields.len()) {
+            ("pin", 4) => {
+                let pin = Pin { uid: parse(fields[1])? as u32, gid: parse(fields[2])? as u32, status: PinStatus::Pending, synced: None };
+                state.roots.insert(path, pin);
+            }
+            ("dir", 4) => {
+                let mut metadata = directory_metadata();
+                metadata.perm = parse(fields[1])? as u16;
+                metadata.mtime = Timespec::new(parse(fields[2])? as i64, 0);
+                state.insert_entry(path, metadata);
+            }
+            ("file", 6) => {
+                let mut metadata = directory_metadata();
+                metadata.kind = FileType::RegularFile;
+                metadata.perm = parse(fields[1])? as u16;
+                metadata.mtime = Timespec::new(parse(fields[2])? as i64, 0);
+                metadata.size = parse(fields[3])?;
+                metadata.version = match fields[4] {
+                    "-" => None,
+                    version => Some(decode_path(version).and_then(|version| version.to_str().map(String::from))
+                        .ok_or_else(|| invalid_index(&line))?),
+                };
+                state.insert_entry(path, metadata);
+            }
+            _ => return Err(invalid_index(&line)),
+        }
+    }
+    Ok(state)
+}
+
+// Rewrites the index and the status file
+fn save_index(dir: &Path, state: &PinState) -> io::Result<()> {
+    let index_file = dir.join("pins");
+    let tmp_file = dir.join("pins.tmp");
+    {
+        let file = File::create(&tmp_file)?;
+        let mut writer = BufWriter::new(&file);
+        writeln!(writer, "{}", PINS_HEADER)?;
+        for (path, pin) in &state.roots {
+            writeln!(writer, "pin {} {} {}", pin.uid, pin.gid, encode_path(path))?;
+        }
+        for (path, metadata) in &state.entries {
+            let mtime = metadata.mtime.sec.max(0);
+            match metadata.kind {
+                FileType::Directory => writeln!(writer, "dir {} {} {}", metadata.perm, mtime, encode_path(path))?,
+                _ => {
+                    let version = metadata.version.as_ref()
+                        .map(|version| encode_path(Path::new(version)))
+                        .unwrap_or_else(|| "-".into());
+                    writeln!(writer, "file {} {} {} {} {}", metadata.perm, mtime, metadata.size, version, encode_path(path))?;
+                }
+            }
+        }
+        writer.flush()?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_file, &index_file)?;
+
+    let mut status = String::new();
+    for (path, pin) in &state.roots {
+        let line = match pin.status {
+            PinStatus::Pending => "pending".to_owned(),
+            PinStatus::Syncing { files } => format!("syncing {}", files),
+            PinStatus::Synced { files, at } => {
+                let at = at.duration_since(UNIX_EPOCH).map(|at| at.as_secs()).unwrap_or(0);
+                format!("synced {} {}", files, at)
+            }
+            PinStatus::Failed(err) => format!("failed {}", err),
+        };
+        status.push_str(&format!("{} {}\n", line, encode_path(path)));
+    }
+    fs::write(dir.join("status"), status)
+}
+
+fn invalid_index(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid pin index: {}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    // Backend with a directory `/docs` holding `a.txt`, `sub/b.txt` and a file `/c.txt`
+    // whose version changes with the `version` field. `/docs` also lists names that would
+    // lead outside of it.
+    #[derive(Clone)]
+    struct TreeFs {
+        version: Arc<Mutex<String>>,
+    }
+
+    impl TreeFs {
+        fn file(&self, size: u64) -> Metadata {
+            let mut metadata = directory_metadata();
+            metadata.kind = FileType::RegularFile;
+            metadata.size = size;
+            metadata.version = Some(self.version.lock().unwrap().clone());
+            metadata
+        }
+    }
+
+    impl NetworkFilesystem for TreeFs {
+        fn clone_for_prefetch(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+            Some(Box::new(self.clone()))
+        }
+
+        fn lookup(&mut self, _caller: &Caller, path: &Path) -> Result<Metadata, LibcError> {
+            match path.to_str() {
+                Some("/docs") => Ok(directory_metadata()),
+                Some("/c.txt") => Ok(self.file(1)),
+                _ => Err(libc::ENOENT),
+            }
+        }
+
+        fn readdir(&mut self, _caller: &Caller, path: &Path) -> ::DirIterator {
+            let entries = match path.to_str() {
+                Some("/docs") => vec![
+                    DirEntry::new("a.txt", self.file(1)),
+                    DirEntry::new("sub", directory_metadata()),
+                    DirEntry::new("..", directory_metadata()),
+                    DirEntry::new("../../escaped.txt", self.file(1)),
+                ],
+                Some("/docs/sub") => vec![DirEntry::new("b.txt", self.file(1))],
+                _ => vec![],
+            };
+            Box::new(entries.into_iter().map(Ok))
+        }
+
+        fn read(&mut self, _caller: &Caller, path: &Path, buffer: &mut Vec<u8>) -> Result<usize, LibcError> {
+            let data = format!("{}@{}", path.display(), self.version.lock().unwrap());
+            buffer.extend_from_slice(data.as_bytes());
+            Ok(data.len())
+        }
+    }
+
+    fn wait_for_sync(pins: &Pins, path: &str) {
+        let start = Instant::now();
+        while !matches!(pins.status(path), Some(PinStatus::Synced { .. })) {
+            assert!(start.elapsed() < Duration::from_secs(5), "pin sync timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_pins_sync_and_persist() {
+        let dir = env::temp_dir().join(format!("netfuse-pin-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let backend = TreeFs { version: Arc::new(Mutex::new("v1".into())) };
+
+        let pins = Pins::open(&dir, Duration::from_secs(3600)).unwrap();
+        assert!(pins.start(&backend));
+        pins.pin("/docs");
+        pins.pin("/c.txt");
+        wait_for_sync(&pins, "/docs");
+        wait_for_sync(&pins, "/c.txt");
+
+        assert_eq!(pins.read(Path::new("/docs/sub/b.txt"), Some("v1")), Some(b"/docs/sub/b.txt@v1".to_vec()));
+        assert_eq!(pins.read(Path::new("/docs/sub/b.txt"), Some("v2")), None);
+        assert_eq!(pins.read(Path::new("/c.txt"), None), Some(b"/c.txt@v1".to_vec()));
+        let mut names: Vec<_> = pins.children(Path::new("/docs")).unwrap().into_iter().map(|entry| entry.filename).collect();
+        names.sort();
+        assert_eq!(names, vec!["a.txt", "sub"]);
+        assert_eq!(pins.metadata(Path::new("/docs/sub")).map(|metadata| metadata.kind), Some(FileType::Directory));
+        assert!(pins.children(Path::new("/c.txt")).is_none());
+        assert!(fs::read_to_string(dir.join("status")).unwrap().contains("synced 2 "));
+        assert!(!dir.join("escaped.txt").exists());
+        assert!(pins.shared.file_path(Path::new("/docs/../../escaped.txt")).is_none());
+
+        // The cache survives reopening, and unpinning removes the cached files
+        drop(pins);
+        let pins = Pins::open(&dir, Duration::from_secs(3600)).unwrap();
+        assert_eq!(pins.status("/docs"), Some(PinStatus::Pending));
+        assert_eq!(pins.read(Path::new("/docs/a.txt"), Some("v1")), Some(b"/docs/a.txt@v1".to_vec()));
+        assert!(pins.unpin("/docs"));
+        assert!(!pins.unpin("/docs"));
+        assert!(pins.read(Path::new("/docs/a.txt"), None).is_none());
+        assert!(!dir.join("files/docs").exists());
+        assert!(pins.read(Path::new("/c.txt"), None).is_some());
+
+        // Changed files are fetched again
+        *backend.version.lock().unwrap() = "v2".into();
+        assert!(pins.start(&backend));
+        wait_for_sync(&pins, "/c.txt");
+        assert_eq!(pins.read(Path::new("/c.txt"), Some("v2")), Some(b"/c.txt@v2".to_vec()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use super::{Caller, LibcError, NetworkFilesystem};
+
+/// Options for prefetching small files into the data cache in the background
+///
+/// Prefetching starts after a directory has been fully listed, or after files in the same
+/// directory are opened one after another, and only covers regular files up to `max_file_size`.
+#[derive(Debug, Copy, Clone)]
+pub struct PrefetchOptions {
+    /// Largest file, in bytes, that will be prefetched
+    pub max_file_size: u64,
+    /// Upper bound on bytes being prefetched plus prefetched data not opened yet
+    pub max_bytes: u64,
+    /// Number of background reads that may run at once
+    pub concurrency: usize,
+    /// Number of following siblings to prefetch once files in a directory are opened in sequence
+    pub sequential_window: usize,
+}
+
+impl Default for PrefetchOptions {
+    fn default() -> PrefetchOptions {
+        PrefetchOptions {
+            max_file_size: 1024 * 1024,
+            max_bytes: 64 * 1024 * 1024,
+            concurrency: 4,
+            sequential_window: 16,
+        }
+    }
+}
+
+pub type PrefetchResult = (u64, Result<Vec<u8>, LibcError>);
+
+// Pool of worker threads reading files with their own handles to the backend
+#[derive(Debug)]
+pub struct Prefetcher {
+    pub options: PrefetchOptions,
+    jobs: Sender<(u64, PathBuf, Caller)>,
+    results: Receiver<PrefetchResult>,
+    // inodes queued or being read, with their expected size
+    pending: HashMap<u64, u64>,
+    pending_bytes: u64,
+}
+
+impl Prefetcher {
+    // Starts one worker for each backend handle that `clone_for_prefetch` provides,
+    // up to the configured concurrency. Returns None if the backend provides none.
+    pub fn start<NFS: NetworkFilesystem>(nfs: &NFS, options: PrefetchOptions) -> Option<Prefetcher> {
+        let (jobs, job_queue) = mpsc::channel::<(u64, PathBuf, Caller)>();
+        let (result_sender, results) = mpsc::channel();
+        let job_queue = Arc::new(Mutex::new(job_queue));
+
+        let mut workers = 0;
+        while workers < options.concurrency {
+            let mut reader = match nfs.clone_for_prefetch() {
+                Some(reader) => reader,
+                None => break,
+            };
+            let job_queue = job_queue.clone();
+            let result_sender = result_sender.clone();
+            thread::spawn(move || loop {
+                let job = job_queue.lock().unwrap().recv();
+                let (ino, path, caller) = match job {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                let mut buffer = Vec::new();
+                let result = reader.read(&caller, &path, &mut buffer).map(|_| buffer);
+                if result_sender.send((ino, result)).is_err() {
+                    return;
+                }
+            });
+            workers += 1;
+        }
+
+        match workers {
+            0 => None,
+            _ => Some(Prefetcher {
+                options: options,
+                jobs: jobs,
+                results: results,
+                pending: HashMap::new(),
+                pending_bytes: 0,
+            }),
+        }
+    }
+
+    pub fn is_pending(&self, ino: u64) -> bool {
+        self.pending.contains_key(&ino)
+    }
+
+    pub fn pending_bytes(&self) -> u64 {
+        self.pending_bytes
+    }
+
+    // Queues a file to be read on behalf of `caller`, reserving `size` bytes until the read finishes
+    pub fn queue(&mut self, ino: u64, path: PathBuf, size: u64, caller: &Caller) {
+        if let Entry::Vacant(pending) = self.pending.entry(ino) {
+            debug!("prefetch queued: {} {}", ino, path.display());
+            pending.insert(size);
+            self.pending_bytes += size;
+            let _ = self.jobs.send((ino, path, caller.clone()));
+        }
+    }
+
+    // Returns a finished prefetch, blocking for the next one if `wait` is set
+    pub fn next_result(&mut self, wait: bool) -> Option<PrefetchResult> {
+        let next = match wait {
+            true => self.results.recv().ok(),
+            false => self.results.try_recv().ok(),
+        };
+        if let Some((ino, _)) = next {
+            let size = self.pending.remove(&ino).unwrap_or(0);
+            self.pending_bytes -= size;
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use libc::ENOENT;
+
+    struct EchoFs;
+
+    impl NetworkFilesystem for EchoFs {
+        fn clone_for_prefetch(&self) -> Option<Box<dyn NetworkFilesystem + Send>> {
+            Some(Box::new(EchoFs))
+        }
+
+        fn read(&mut self, _caller: &Caller, path: &Path, buffer: &mut Vec<u8>) -> Result<usize, LibcError> {
+            match path.to_str() {
+                Some("/missing") => Err(ENOENT),
+                Some(name) => {
+                    buffer.extend_from_slice(name.as_bytes());
+                    Ok(name.len())
+                }
+                None => Err(ENOENT),
+            }
+        }
+    }
+
+    #[test]
+    fn test_prefetcher_requires_clone() {
+        struct NoCloneFs;
+        impl NetworkFilesystem for NoCloneFs {}
+        assert!(Prefetcher::start(&NoCloneFs, PrefetchOptions::default()).is_none());
+    }
+
+    #[test]
+    fn test_prefetcher_reads_queued_files() {
+        let caller = Caller::new(1000, 1000, 1);
+        let mut prefetcher = Prefetcher::start(&EchoFs, PrefetchOptions::default()).unwrap();
+        prefetcher.queue(2, PathBuf::from("/foo.txt"), 8, &caller);
+        prefetcher.queue(3, PathBuf::from("/missing"), 10, &caller);
+        prefetcher.queue(2, PathBuf::from("/foo.txt"), 8, &caller);
+        assert!(prefetcher.is_pending(2));
+        assert_eq!(prefetcher.pending_bytes(), 18);
+
+        let mut results = vec![prefetcher.next_result(true).unwrap(), prefetcher.next_result(true).unwrap()];
+        results.sort_by_key(|&(ino, _)| ino);
+        assert_eq!(results, vec![(2, Ok(b"/foo.txt".to_vec())), (3, Err(ENOENT))]);
+        assert!(!prefetcher.is_pending(2));
+        assert_eq!(prefetcher.pending_bytes(), 0);
+        assert!(prefetcher.next_result(false).is_none());
+    }
+}